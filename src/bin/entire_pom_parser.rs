@@ -24,13 +24,13 @@ fn space() -> Parser<u8, ()>
 fn number() -> Parser<u8, f64>
 {
     let integer =
-        one_of(b"123456789") - one_of(b"0123456789").repeat(0 ..) | sym(b'0');
+        (one_of(b"123456789") - one_of(b"0123456789").repeat(0 ..)) | sym(b'0');
     let frac = sym(b'.') + one_of(b"0123456789").repeat(1 ..);
     let exp = one_of(b"eE")
         + one_of(b"+-").opt()
         + one_of(b"0123456789").repeat(1 ..);
     let number = sym(b'-').opt() + integer + frac.opt() + exp.opt();
-    number.collect().convert(str::from_utf8).convert(|s| f64::from_str(&s))
+    number.collect().convert(str::from_utf8).convert(f64::from_str)
 }
 
 fn string() -> Parser<u8, String>
@@ -69,10 +69,10 @@ fn value() -> Parser<u8, JsonValue>
     (seq(b"null").map(|_| JsonValue::Null)
         | seq(b"true").map(|_| JsonValue::Bool(true))
         | seq(b"false").map(|_| JsonValue::Bool(false))
-        | number().map(|num| JsonValue::Num(num))
-        | string().map(|text| JsonValue::Str(text))
-        | array().map(|arr| JsonValue::Array(arr))
-        | object().map(|obj| JsonValue::Object(obj)))
+        | number().map(JsonValue::Num)
+        | string().map(JsonValue::Str)
+        | array().map(JsonValue::Array)
+        | object().map(JsonValue::Object))
         - space()
 }
 
@@ -0,0 +1,451 @@
+//! What a query parses into, and how it prints back out in canonical form
+
+use crate::Span;
+
+/// Steps that each run on every result of the step before
+#[derive(Debug)]
+pub struct Pipeline
+{
+    pub steps: Vec<Spanned<Query>>,
+}
+
+#[rustfmt::skip]
+#[derive(Debug)]
+pub enum Query
+{
+    SelectKey { key: String, },
+    Index { index: isize, },
+    Slice { start: Option<isize>, end: Option<isize>, step: Option<isize>, },
+    Expression { source: String, },
+    BuildObject { entries: Vec<ObjectEntry>, },
+    Fanout,
+    Recurse { key: String, },
+    Join { branches: Vec<Pipeline>, collect: bool, },
+    Select { source: String, },
+    Variable { name: String, },
+    Builtin { builtin: Builtin, },
+    Assign { path: Pipeline, update: bool, value: Pipeline, },
+    Optional { step: Box<Spanned<Query>>, },
+    Alternative { first: Pipeline, fallback: Pipeline, },
+    Call { name: String, args: Vec<Pipeline>, },
+}
+
+/// `def name($a, $b): body;`, from the query or a library file
+#[derive(Debug)]
+pub struct Function
+{
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Pipeline,
+
+    /// The library file it was loaded from, or `None` for the query itself
+    pub file: Option<String>,
+}
+
+/// Functions implemented natively on JSON, called as `name(...)`
+#[derive(Debug)]
+pub enum Builtin
+{
+    Length,
+    Keys,
+    Values,
+    ToEntries,
+    FromEntries,
+    SortBy(Pipeline),
+    GroupBy(Pipeline),
+    Unique,
+    Min,
+    Max,
+    Add,
+    Delete(Pipeline),
+    Paths,
+    LeafPaths,
+    GetPath(Pipeline),
+    SetPath(Pipeline, Pipeline),
+
+    /// How many levels to flatten, or all of them
+    Flatten(Option<usize>),
+}
+
+/// One `key: value` of `{...}`, where `{a}` is short for `{a: a}` and `{$a}`
+/// for `{a: $a}`
+#[derive(Debug)]
+pub struct ObjectEntry
+{
+    pub key: Spanned<ObjectKey>,
+    pub value: Spanned<ObjectValue>,
+}
+
+#[derive(Debug)]
+pub enum ObjectKey
+{
+    /// A bare key, whose field a Python value sees as `_`
+    Name(String),
+
+    /// A Python expression evaluating to a string, which sees the whole
+    /// object as `_`
+    Python(String),
+}
+
+#[derive(Debug)]
+pub enum ObjectValue
+{
+    Field(String),
+    Variable(String),
+    Python(String),
+}
+
+/// A query along with the byte range of the query string it was parsed from
+#[derive(Debug)]
+pub struct Spanned<T>
+{
+    pub inner: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T>
+{
+    pub fn new(inner: T, span: Span) -> Self
+    {
+        Self { inner, span }
+    }
+}
+
+impl Query
+{
+    /// Whether any part of this step, not counting nested pipelines, is
+    /// evaluated by Python
+    pub fn runs_python(&self) -> bool
+    {
+        match self
+        {
+            Self::Expression { .. } | Self::Select { .. } => true,
+            Self::Optional { step } => step.inner.runs_python(),
+            Self::BuildObject { entries } => entries.iter().any(|entry| {
+                matches!(entry.key.inner, ObjectKey::Python(..))
+                    || matches!(entry.value.inner, ObjectValue::Python(..))
+            }),
+            _ => false,
+        }
+    }
+
+    /// Whether this step can name a location to assign to, rather than only
+    /// produce a value
+    pub fn is_path(&self) -> bool
+    {
+        match self
+        {
+            Self::Optional { step } => step.inner.is_path(),
+            _ => matches!(
+                self,
+                Self::SelectKey { .. }
+                    | Self::Index { .. }
+                    | Self::Fanout
+                    | Self::Recurse { .. }
+                    | Self::Select { .. }
+            ),
+        }
+    }
+
+    /// Pipelines nested inside this step, not counting an optional step's
+    pub fn pipelines(&self) -> Vec<&Pipeline>
+    {
+        match self
+        {
+            Self::Join { branches, .. } => branches.iter().collect(),
+            Self::Builtin { builtin } => match builtin
+            {
+                Builtin::SortBy(pipeline)
+                | Builtin::GroupBy(pipeline)
+                | Builtin::Delete(pipeline)
+                | Builtin::GetPath(pipeline) => vec![pipeline],
+                Builtin::SetPath(path, value) => vec![path, value],
+                _ => vec![],
+            },
+            Self::Assign { path, value, .. } => vec![path, value],
+            Self::Alternative { first, fallback } => vec![first, fallback],
+            Self::Call { args, .. } => args.iter().collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Steps printed after another one need a `.` to separate them
+    fn needs_dot(&self) -> bool
+    {
+        if let Self::Optional { step } = self
+        {
+            return step.inner.needs_dot();
+        }
+
+        matches!(
+            self,
+            Self::SelectKey { .. }
+                | Self::Expression { .. }
+                | Self::BuildObject { .. }
+                | Self::Variable { .. }
+                | Self::Builtin { .. }
+                | Self::Call { .. }
+        )
+    }
+}
+
+impl Builtin
+{
+    pub const NAMES: [&'static str; 17] = [
+        "length",
+        "keys",
+        "values",
+        "to_entries",
+        "from_entries",
+        "sort_by",
+        "group_by",
+        "unique",
+        "min",
+        "max",
+        "add",
+        "del",
+        "paths",
+        "leaf_paths",
+        "getpath",
+        "setpath",
+        "flatten",
+    ];
+
+    pub fn name(&self) -> &'static str
+    {
+        match self
+        {
+            Self::Length => "length",
+            Self::Keys => "keys",
+            Self::Values => "values",
+            Self::ToEntries => "to_entries",
+            Self::FromEntries => "from_entries",
+            Self::SortBy(..) => "sort_by",
+            Self::GroupBy(..) => "group_by",
+            Self::Unique => "unique",
+            Self::Min => "min",
+            Self::Max => "max",
+            Self::Add => "add",
+            Self::Delete(..) => "del",
+            Self::Paths => "paths",
+            Self::LeafPaths => "leaf_paths",
+            Self::GetPath(..) => "getpath",
+            Self::SetPath(..) => "setpath",
+            Self::Flatten(..) => "flatten",
+        }
+    }
+}
+
+fn is_identifier(key: &str) -> bool
+{
+    let mut chars = key.chars();
+    chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// Prints `key` bare when it is an identifier, or as a JSON string otherwise
+fn fmt_key(f: &mut std::fmt::Formatter<'_>, key: &str) -> std::fmt::Result
+{
+    if is_identifier(key)
+    {
+        write!(f, "{key}")
+    }
+    else
+    {
+        write!(f, "{}", serde_json::Value::from(key))
+    }
+}
+
+fn fmt_bound(
+    f: &mut std::fmt::Formatter<'_>,
+    bound: Option<isize>,
+) -> std::fmt::Result
+{
+    match bound
+    {
+        Some(bound) => write!(f, "{bound}"),
+        None => Ok(()),
+    }
+}
+
+impl std::fmt::Display for Pipeline
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        if self.steps.is_empty()
+        {
+            return write!(f, ".");
+        }
+
+        for (i, step) in self.steps.iter().enumerate()
+        {
+            if i > 0 && step.inner.needs_dot()
+            {
+                write!(f, ".")?;
+            }
+            write!(f, "{}", step.inner)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Query
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            Self::SelectKey { key } => fmt_key(f, key),
+            Self::Index { index } => write!(f, "[{index}]"),
+            Self::Slice { start, end, step } =>
+            {
+                write!(f, "[")?;
+                fmt_bound(f, *start)?;
+                write!(f, ":")?;
+                fmt_bound(f, *end)?;
+                if step.is_some()
+                {
+                    write!(f, ":")?;
+                    fmt_bound(f, *step)?;
+                }
+                write!(f, "]")
+            }
+            Self::Expression { source } => write!(f, "{source}"),
+            Self::BuildObject { entries } =>
+            {
+                write!(f, "{{")?;
+                for (i, entry) in entries.iter().enumerate()
+                {
+                    if i > 0
+                    {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{entry}")?;
+                }
+                write!(f, "}}")
+            }
+            Self::Fanout => write!(f, "[]"),
+            Self::Recurse { key } =>
+            {
+                write!(f, "..")?;
+                fmt_key(f, key)
+            }
+            Self::Join { branches, collect } =>
+            {
+                if *collect
+                {
+                    write!(f, "[")?;
+                }
+                for (i, branch) in branches.iter().enumerate()
+                {
+                    if i > 0
+                    {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{branch}")?;
+                }
+                if *collect
+                {
+                    write!(f, "]")?;
+                }
+                Ok(())
+            }
+            Self::Select { source } => write!(f, "?{source}"),
+            Self::Variable { name } => write!(f, "${name}"),
+            Self::Builtin { builtin } => write!(f, "{builtin}"),
+            Self::Assign { path, update, value } =>
+            {
+                let op = if *update { "|=" } else { "=" };
+                write!(f, "{path} {op} {value}")
+            }
+            Self::Optional { step } => write!(f, "{}?", step.inner),
+            Self::Call { name, args } =>
+            {
+                write!(f, "{name}(")?;
+                for (i, arg) in args.iter().enumerate()
+                {
+                    if i > 0
+                    {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{arg}")?;
+                }
+                write!(f, ")")
+            }
+            Self::Alternative { first, fallback } =>
+            {
+                write!(f, "{first} // {fallback}")
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Builtin
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            Self::SortBy(key)
+            | Self::GroupBy(key)
+            | Self::Delete(key)
+            | Self::GetPath(key) =>
+            {
+                write!(f, "{}({key})", self.name())
+            }
+            Self::SetPath(path, value) =>
+            {
+                write!(f, "{}({path}, {value})", self.name())
+            }
+            Self::Flatten(Some(depth)) => write!(f, "{}({depth})", self.name()),
+            _ => write!(f, "{}()", self.name()),
+        }
+    }
+}
+
+impl std::fmt::Display for ObjectEntry
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match (&self.key.inner, &self.value.inner)
+        {
+            (ObjectKey::Name(key), ObjectValue::Field(field))
+                if key == field =>
+            {
+                write!(f, "{key}")
+            }
+            (ObjectKey::Name(key), ObjectValue::Variable(name))
+                if key == name =>
+            {
+                write!(f, "${name}")
+            }
+            (key, value) => write!(f, "{key}: {value}"),
+        }
+    }
+}
+
+impl std::fmt::Display for ObjectKey
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            Self::Name(name) => write!(f, "{name}"),
+            Self::Python(source) => write!(f, "{source}"),
+        }
+    }
+}
+
+impl std::fmt::Display for ObjectValue
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            Self::Field(field) => write!(f, "{field}"),
+            Self::Variable(name) => write!(f, "${name}"),
+            Self::Python(source) => write!(f, "{source}"),
+        }
+    }
+}
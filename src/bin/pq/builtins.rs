@@ -0,0 +1,399 @@
+//! Built-in functions that work on JSON directly, without a round trip through
+//! Python
+
+use std::cmp::Ordering;
+
+use serde_json::{Map, Value};
+
+use crate::ast::{Builtin, Pipeline};
+use crate::{evaluate, path, type_name, Env, PqError, Span};
+
+pub fn call(
+    env: &Env,
+    builtin: &Builtin,
+    value: Value,
+    span: &Span,
+) -> Result<Value, PqError>
+{
+    let type_error = |value: &Value| PqError::Type {
+        message: format!(
+            "cannot call {}() on {}",
+            builtin.name(),
+            type_name(value)
+        ),
+        span: span.clone(),
+    };
+
+    let result = match (builtin, value)
+    {
+        (Builtin::Length, Value::Null) => Value::from(0),
+        (Builtin::Length, Value::Number(number)) =>
+        {
+            match (number.as_i64(), number.as_f64())
+            {
+                (Some(int), _) => Value::from(int.unsigned_abs()),
+                (None, float) => Value::from(float.unwrap_or_default().abs()),
+            }
+        }
+        (Builtin::Length, Value::String(string)) =>
+        {
+            Value::from(string.chars().count())
+        }
+        (Builtin::Length, Value::Array(array)) => Value::from(array.len()),
+        (Builtin::Length, Value::Object(object)) => Value::from(object.len()),
+
+        (Builtin::Keys, Value::Object(object)) =>
+        {
+            let mut keys: Vec<_> = object.into_iter().map(|(k, _)| k).collect();
+            keys.sort();
+            Value::from(keys)
+        }
+        (Builtin::Keys, Value::Array(array)) =>
+        {
+            Value::from((0 .. array.len()).collect::<Vec<_>>())
+        }
+
+        (Builtin::Values, Value::Object(object)) =>
+        {
+            Value::Array(object.into_iter().map(|(_, v)| v).collect())
+        }
+        (Builtin::Values, value @ Value::Array(_)) => value,
+
+        (Builtin::ToEntries, Value::Object(object)) => object
+            .into_iter()
+            .map(|(key, value)| serde_json::json!({"key": key, "value": value}))
+            .collect(),
+        (Builtin::FromEntries, Value::Array(entries)) =>
+        {
+            let mut object = Map::new();
+            for entry in entries
+            {
+                let (key, value) =
+                    from_entry(&entry).ok_or_else(|| PqError::Type {
+                        message: format!(
+                            "cannot use {} as an entry with a key and value",
+                            type_name(&entry)
+                        ),
+                        span: span.clone(),
+                    })?;
+                object.insert(key, value);
+            }
+            Value::Object(object)
+        }
+
+        (Builtin::SortBy(key), Value::Array(array)) =>
+        {
+            let mut keyed = keyed(env, key, array)?;
+            keyed.sort_by(|(a, _), (b, _)| compare(a, b));
+            keyed.into_iter().map(|(_, value)| value).collect()
+        }
+        (Builtin::GroupBy(key), Value::Array(array)) =>
+        {
+            let mut keyed = keyed(env, key, array)?;
+            keyed.sort_by(|(a, _), (b, _)| compare(a, b));
+
+            let mut groups: Vec<(Value, Vec<Value>)> = vec![];
+            for (key, value) in keyed
+            {
+                match groups.last_mut()
+                {
+                    Some((last, group)) if compare(last, &key).is_eq() =>
+                    {
+                        group.push(value)
+                    }
+                    _ => groups.push((key, vec![value])),
+                }
+            }
+            groups.into_iter().map(|(_, group)| Value::Array(group)).collect()
+        }
+        (Builtin::Unique, Value::Array(mut array)) =>
+        {
+            array.sort_by(compare);
+            array.dedup_by(|a, b| compare(a, b).is_eq());
+            Value::Array(array)
+        }
+        (Builtin::Min, Value::Array(array)) =>
+        {
+            array.into_iter().min_by(compare).unwrap_or_default()
+        }
+        (Builtin::Max, Value::Array(array)) =>
+        {
+            array.into_iter().max_by(compare).unwrap_or_default()
+        }
+        (Builtin::Add, Value::Array(array)) =>
+        {
+            let mut sum = Value::Null;
+            for value in array
+            {
+                sum = add(sum, value, span)?;
+            }
+            sum
+        }
+        (Builtin::Delete(path), mut value) =>
+        {
+            let paths = path::paths(env, &value, path)?;
+            path::delete(&mut value, paths);
+            value
+        }
+        (Builtin::Paths, value) =>
+        {
+            path::all_paths(&value, false).iter().map(path::to_json).collect()
+        }
+        (Builtin::LeafPaths, value) =>
+        {
+            path::all_paths(&value, true).iter().map(path::to_json).collect()
+        }
+        (Builtin::GetPath(path), value) =>
+        {
+            let path = first(env, builtin, path, &value, span)?;
+            path::get_path(&value, &path::from_json(&path, span)?, span)?
+        }
+        (Builtin::SetPath(path, new), mut value) =>
+        {
+            let path = first(env, builtin, path, &value, span)?;
+            let new = first(env, builtin, new, &value, span)?;
+            let path = path::from_json(&path, span)?;
+            path::update(&mut value, &path, span, &mut |_| Ok(new.clone()))?;
+            value
+        }
+        (Builtin::Flatten(depth), Value::Array(array)) =>
+        {
+            let mut flat = vec![];
+            flatten(array, depth.unwrap_or(usize::MAX), &mut flat);
+            Value::Array(flat)
+        }
+
+        (_, value) => return Err(type_error(&value)),
+    };
+
+    Ok(result)
+}
+
+/// The first result of an argument, which has to give back something
+fn first(
+    env: &Env,
+    builtin: &Builtin,
+    argument: &Pipeline,
+    value: &Value,
+    span: &Span,
+) -> Result<Value, PqError>
+{
+    let results = evaluate(env, value.clone(), &argument.steps)?;
+    results.into_iter().next().ok_or_else(|| PqError::Type {
+        message: format!(
+            "`{argument}` gave {}() nothing to work with",
+            builtin.name()
+        ),
+        span: span.clone(),
+    })
+}
+
+/// Pairs every item with the results of running `key` on it, collected into
+/// an array so keys producing any number of results still compare
+fn keyed(
+    env: &Env,
+    key: &Pipeline,
+    array: Vec<Value>,
+) -> Result<Vec<(Value, Value)>, PqError>
+{
+    array
+        .into_iter()
+        .map(|value| {
+            let keys = evaluate(env, value.clone(), &key.steps)?;
+            Ok((Value::Array(keys), value))
+        })
+        .collect()
+}
+
+/// The key and value of `{"key": k, "value": v}`, also accepting the names
+/// `k`, `name`, `v` and their capitalized forms
+fn from_entry(entry: &Value) -> Option<(String, Value)>
+{
+    let object = entry.as_object()?;
+    let key = ["key", "k", "name", "Key", "K", "Name"]
+        .iter()
+        .find_map(|name| object.get(*name))?;
+    let value = ["value", "v", "Value", "V"]
+        .iter()
+        .find_map(|name| object.get(*name))
+        .cloned()
+        .unwrap_or_default();
+
+    let key = match key
+    {
+        Value::String(key) => key.clone(),
+        Value::Number(..) | Value::Bool(..) => key.to_string(),
+        _ => return None,
+    };
+
+    Some((key, value))
+}
+
+/// `null` is the identity, otherwise both sides have to be the same type
+fn add(a: Value, b: Value, span: &Span) -> Result<Value, PqError>
+{
+    let sum = match (a, b)
+    {
+        (Value::Null, b) => b,
+        (a, Value::Null) => a,
+        (Value::Number(a), Value::Number(b)) => match (a.as_i64(), b.as_i64())
+        {
+            (Some(a), Some(b)) if a.checked_add(b).is_some() =>
+            {
+                Value::from(a + b)
+            }
+            _ => Value::from(
+                a.as_f64().unwrap_or_default() + b.as_f64().unwrap_or_default(),
+            ),
+        },
+        (Value::String(a), Value::String(b)) => Value::String(a + &b),
+        (Value::Array(mut a), Value::Array(b)) =>
+        {
+            a.extend(b);
+            Value::Array(a)
+        }
+        (Value::Object(mut a), Value::Object(b)) =>
+        {
+            a.extend(b);
+            Value::Object(a)
+        }
+        (a, b) =>
+        {
+            return Err(PqError::Type {
+                message: format!(
+                    "cannot add {} and {}",
+                    type_name(&a),
+                    type_name(&b)
+                ),
+                span: span.clone(),
+            });
+        }
+    };
+
+    Ok(sum)
+}
+
+fn flatten(array: Vec<Value>, depth: usize, flat: &mut Vec<Value>)
+{
+    for value in array
+    {
+        match value
+        {
+            Value::Array(inner) if depth > 0 => flatten(inner, depth - 1, flat),
+            value => flat.push(value),
+        }
+    }
+}
+
+/// Orders values the way jq does: `null`, `false`, `true`, numbers, strings,
+/// arrays, then objects, which compare by their sorted keys first
+fn compare(a: &Value, b: &Value) -> Ordering
+{
+    fn rank(value: &Value) -> u8
+    {
+        match value
+        {
+            Value::Null => 0,
+            Value::Bool(false) => 1,
+            Value::Bool(true) => 2,
+            Value::Number(_) => 3,
+            Value::String(_) => 4,
+            Value::Array(_) => 5,
+            Value::Object(_) => 6,
+        }
+    }
+
+    match (a, b)
+    {
+        (Value::Number(a), Value::Number(b)) =>
+        {
+            let (a, b) = (a.as_f64(), b.as_f64());
+            a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+        }
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (Value::Array(a), Value::Array(b)) =>
+        {
+            for (a, b) in a.iter().zip(b)
+            {
+                let ordering = compare(a, b);
+                if ordering.is_ne()
+                {
+                    return ordering;
+                }
+            }
+            a.len().cmp(&b.len())
+        }
+        (Value::Object(a), Value::Object(b)) =>
+        {
+            let mut a_keys: Vec<_> = a.keys().collect();
+            let mut b_keys: Vec<_> = b.keys().collect();
+            a_keys.sort();
+            b_keys.sort();
+
+            a_keys.cmp(&b_keys).then_with(|| {
+                a_keys
+                    .iter()
+                    .map(|key| compare(&a[*key], &b[*key]))
+                    .find(|ordering| ordering.is_ne())
+                    .unwrap_or(Ordering::Equal)
+            })
+        }
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn compare_orders_types_like_jq()
+    {
+        let ordered = [
+            json!(null),
+            json!(false),
+            json!(true),
+            json!(-1),
+            json!(0.5),
+            json!(10),
+            json!(""),
+            json!("a"),
+            json!([]),
+            json!({}),
+        ];
+        for (i, a) in ordered.iter().enumerate()
+        {
+            for (j, b) in ordered.iter().enumerate()
+            {
+                assert_eq!(compare(a, b), i.cmp(&j), "{a} against {b}");
+            }
+        }
+    }
+
+    #[test]
+    fn compare_arrays_item_by_item_then_by_length()
+    {
+        assert_eq!(compare(&json!([1, 2]), &json!([1, 3])), Ordering::Less);
+        assert_eq!(compare(&json!([2]), &json!([1, 3])), Ordering::Greater);
+        assert_eq!(compare(&json!([1]), &json!([1, 0])), Ordering::Less);
+    }
+
+    #[test]
+    fn compare_objects_by_sorted_keys_then_values()
+    {
+        let a = json!({"b": 1, "a": 2});
+        assert_eq!(compare(&a, &json!({"a": 2, "b": 1})), Ordering::Equal);
+        assert_eq!(compare(&a, &json!({"a": 1, "c": 0})), Ordering::Less);
+        assert_eq!(compare(&a, &json!({"a": 1, "b": 1})), Ordering::Greater);
+    }
+
+    #[test]
+    fn compare_numbers_across_ints_and_floats()
+    {
+        assert_eq!(compare(&json!(1), &json!(1.0)), Ordering::Equal);
+        assert_eq!(compare(&json!(2), &json!(1.5)), Ordering::Greater);
+    }
+}
@@ -0,0 +1,292 @@
+//! `--explain`: how a query was parsed, what each step takes and gives back,
+//! and which steps hand their work to Python
+
+use crate::ast::{Builtin, Pipeline, Query};
+
+/// What is statically known about a value flowing between steps
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shape
+{
+    Any,
+    Null,
+    Number,
+    Array,
+    Object,
+    String,
+}
+
+impl std::fmt::Display for Shape
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            Self::Any => write!(f, "any"),
+            Self::Null => write!(f, "null"),
+            Self::Number => write!(f, "number"),
+            Self::Array => write!(f, "array"),
+            Self::Object => write!(f, "object"),
+            Self::String => write!(f, "string"),
+        }
+    }
+}
+
+/// How many values come out of a step for every value that goes in
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Count
+{
+    One,
+    AtMostOne,
+    Many,
+}
+
+impl Count
+{
+    fn then(self, next: Count) -> Count
+    {
+        match (self, next)
+        {
+            (Count::Many, _) | (_, Count::Many) => Count::Many,
+            (Count::AtMostOne, _) | (_, Count::AtMostOne) => Count::AtMostOne,
+            _ => Count::One,
+        }
+    }
+}
+
+struct Row
+{
+    indent: usize,
+    text: String,
+    description: String,
+}
+
+/// Renders `pipeline` as a table of steps, starting from a value of `input`
+pub fn explain(pipeline: &Pipeline, input: Shape) -> String
+{
+    let mut rows = vec![];
+    explain_pipeline(pipeline, input, Count::One, 1, &mut rows);
+
+    let width = rows
+        .iter()
+        .map(|row| row.indent * 2 + row.text.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    let mut explanation = format!("{pipeline}\n");
+    for Row { indent, text, description } in rows
+    {
+        let text = format!("{}{text}", "  ".repeat(indent));
+        let row = format!("{text:width$}  {description}");
+        explanation.push_str(row.trim_end());
+        explanation.push('\n');
+    }
+
+    explanation
+}
+
+/// Adds a row for every step, returning the shape and count that come out
+fn explain_pipeline(
+    pipeline: &Pipeline,
+    mut shape: Shape,
+    mut count: Count,
+    indent: usize,
+    rows: &mut Vec<Row>,
+) -> (Shape, Count)
+{
+    for step in &pipeline.steps
+    {
+        let (output, step_count, problem) = step_shape(&step.inner, shape);
+        let total = count.then(step_count);
+
+        let mut description = format!("{shape} -> {}", describe(output, total));
+        if step.inner.runs_python()
+        {
+            description.push_str("  (Python)");
+        }
+        if let Some(problem) = problem
+        {
+            description.push_str(&format!("  error: {problem}"));
+        }
+        rows.push(Row { indent, text: step.inner.to_string(), description });
+
+        if let Query::Join { branches, .. } = &step.inner
+        {
+            for branch in branches
+            {
+                rows.push(Row {
+                    indent: indent + 1,
+                    text: format!("branch {branch}"),
+                    description: String::new(),
+                });
+                explain_pipeline(branch, shape, count, indent + 2, rows);
+            }
+        }
+        let nested = match &step.inner
+        {
+            Query::Builtin {
+                builtin: Builtin::SortBy(key) | Builtin::GroupBy(key),
+            } => vec![("key", key, Shape::Any)],
+            Query::Builtin {
+                builtin: Builtin::Delete(path) | Builtin::GetPath(path),
+            } => vec![("path", path, shape)],
+            Query::Builtin { builtin: Builtin::SetPath(path, value) } =>
+            {
+                vec![("path", path, shape), ("value", value, shape)]
+            }
+            Query::Assign { path, update, value } =>
+            {
+                let value_shape = if *update { Shape::Any } else { shape };
+                vec![("path", path, shape), ("value", value, value_shape)]
+            }
+            Query::Alternative { first, fallback } =>
+            {
+                vec![("first", first, shape), ("else", fallback, shape)]
+            }
+            Query::Call { args, .. } =>
+            {
+                args.iter().map(|arg| ("argument", arg, shape)).collect()
+            }
+            _ => vec![],
+        };
+        for (name, pipeline, input) in nested
+        {
+            rows.push(Row {
+                indent: indent + 1,
+                text: format!("{name} {pipeline}"),
+                description: String::new(),
+            });
+            explain_pipeline(pipeline, input, Count::One, indent + 2, rows);
+        }
+
+        (shape, count) = (output, total);
+    }
+
+    (shape, count)
+}
+
+fn describe(shape: Shape, count: Count) -> String
+{
+    match count
+    {
+        Count::One => shape.to_string(),
+        Count::AtMostOne => format!("{shape} or nothing"),
+        Count::Many => format!("stream of {shape}"),
+    }
+}
+
+/// The shape that comes out of `query`, and why it fails when `input` is
+/// known to be something it cannot handle
+fn step_shape(query: &Query, input: Shape) -> (Shape, Count, Option<String>)
+{
+    let accepts = |shapes: &[Shape]| {
+        matches!(input, Shape::Any) || shapes.contains(&input)
+    };
+    let problem =
+        |action: String| Some(format!("cannot {action} {}", article(input)));
+
+    match query
+    {
+        Query::SelectKey { key } if !accepts(&[Shape::Null, Shape::Object]) =>
+        {
+            (
+                Shape::Any,
+                Count::One,
+                problem(format!("select key `{key}` from")),
+            )
+        }
+        Query::Index { .. } if !accepts(&[Shape::Null, Shape::Array]) =>
+        {
+            (Shape::Any, Count::One, problem("index".to_string()))
+        }
+        Query::Slice { .. }
+            if !accepts(&[Shape::Null, Shape::Array, Shape::String]) =>
+        {
+            (Shape::Any, Count::One, problem("slice".to_string()))
+        }
+        Query::Fanout if !accepts(&[Shape::Array, Shape::Object]) =>
+        {
+            (Shape::Any, Count::Many, problem("fan out over".to_string()))
+        }
+        Query::SelectKey { .. } | Query::Index { .. }
+            if input == Shape::Null =>
+        {
+            (Shape::Null, Count::One, None)
+        }
+        Query::SelectKey { .. } | Query::Index { .. } =>
+        {
+            (Shape::Any, Count::One, None)
+        }
+        Query::Slice { .. } => (input, Count::One, None),
+        Query::Expression { .. } | Query::Variable { .. } =>
+        {
+            (Shape::Any, Count::One, None)
+        }
+        Query::BuildObject { .. } => (Shape::Object, Count::One, None),
+        Query::Fanout | Query::Recurse { .. } =>
+        {
+            (Shape::Any, Count::Many, None)
+        }
+        Query::Join { collect: true, .. } => (Shape::Array, Count::One, None),
+        Query::Join { collect: false, .. } => (Shape::Any, Count::Many, None),
+        Query::Select { .. } => (input, Count::AtMostOne, None),
+        Query::Builtin { builtin } => builtin_shape(builtin, input),
+        Query::Assign { .. } => (input, Count::One, None),
+        // Whatever would have failed is skipped instead
+        Query::Optional { step } =>
+        {
+            let (output, count, _) = step_shape(&step.inner, input);
+            (output, count.then(Count::AtMostOne), None)
+        }
+        Query::Alternative { .. } => (Shape::Any, Count::One, None),
+        // Nothing is known about the function until the query runs
+        Query::Call { .. } => (Shape::Any, Count::Many, None),
+    }
+}
+
+fn builtin_shape(
+    builtin: &Builtin,
+    input: Shape,
+) -> (Shape, Count, Option<String>)
+{
+    use Shape::{Array, Null, Number, Object, String};
+    let accepts: &[Shape] = match builtin
+    {
+        Builtin::Length => &[Null, Number, String, Array, Object],
+        Builtin::Keys | Builtin::Values => &[Array, Object],
+        Builtin::ToEntries => &[Object],
+        Builtin::Delete(..) | Builtin::SetPath(..) =>
+        {
+            return (input, Count::One, None);
+        }
+        Builtin::GetPath(..) => return (Shape::Any, Count::One, None),
+        Builtin::Paths | Builtin::LeafPaths =>
+        {
+            return (Shape::Array, Count::One, None);
+        }
+        _ => &[Array],
+    };
+    if input != Shape::Any && !accepts.contains(&input)
+    {
+        let problem = format!("cannot call {builtin} on {}", article(input));
+        return (Shape::Any, Count::One, Some(problem));
+    }
+
+    let output = match builtin
+    {
+        Builtin::Length => Shape::Number,
+        Builtin::FromEntries => Shape::Object,
+        Builtin::Min | Builtin::Max | Builtin::Add => Shape::Any,
+        _ => Shape::Array,
+    };
+    (output, Count::One, None)
+}
+
+fn article(shape: Shape) -> String
+{
+    match shape
+    {
+        Shape::Null => shape.to_string(),
+        Shape::Array | Shape::Object | Shape::Any => format!("an {shape}"),
+        _ => format!("a {shape}"),
+    }
+}
@@ -0,0 +1,292 @@
+//! `--from` and `--to`: reading YAML, TOML and CSV into JSON values for the
+//! query, and writing results back out in them
+
+use std::io::{Read, Write};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{type_name, Output, PqError};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format
+{
+    Json,
+
+    /// One compact JSON value per line
+    Ndjson,
+
+    Yaml,
+    Toml,
+
+    /// A row per value, with the first object's keys as the header that every
+    /// other object has to fit in
+    Csv,
+}
+
+impl Format
+{
+    pub fn parse(name: &str) -> Option<Self>
+    {
+        match name
+        {
+            "json" => Some(Self::Json),
+            "ndjson" | "jsonl" => Some(Self::Ndjson),
+            "yaml" | "yml" => Some(Self::Yaml),
+            "toml" => Some(Self::Toml),
+            "csv" => Some(Self::Csv),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Format
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            Self::Json => write!(f, "JSON"),
+            Self::Ndjson => write!(f, "NDJSON"),
+            Self::Yaml => write!(f, "YAML"),
+            Self::Toml => write!(f, "TOML"),
+            Self::Csv => write!(f, "CSV"),
+        }
+    }
+}
+
+/// Calls `each` with every value in `reader`: every document of a YAML
+/// stream, the one table of a TOML file or every row of a CSV file. JSON is
+/// streamed by `process_stream` instead.
+pub fn read(
+    format: Format,
+    mut reader: impl Read,
+    mut each: impl FnMut(Value) -> Result<(), PqError>,
+) -> Result<(), PqError>
+{
+    let invalid = |err: &dyn std::fmt::Display| {
+        PqError::Format(format!("invalid {format} input: {err}"))
+    };
+
+    match format
+    {
+        Format::Json | Format::Ndjson =>
+        {
+            unreachable!("JSON input goes through `process_stream`")
+        }
+        Format::Yaml =>
+        {
+            for document in serde_yaml::Deserializer::from_reader(reader)
+            {
+                each(
+                    Value::deserialize(document)
+                        .map_err(|err| invalid(&err))?,
+                )?;
+            }
+        }
+        Format::Toml =>
+        {
+            let mut text = String::new();
+            reader.read_to_string(&mut text)?;
+            let table: toml::Table =
+                text.parse().map_err(|err| invalid(&err))?;
+            each(from_toml(toml::Value::Table(table)))?;
+        }
+        Format::Csv =>
+        {
+            let mut csv = csv::Reader::from_reader(reader);
+            let headers = csv.headers().map_err(|err| invalid(&err))?.clone();
+            for record in csv.records()
+            {
+                let record = record.map_err(|err| invalid(&err))?;
+                let row = headers
+                    .iter()
+                    .zip(&record)
+                    .map(|(key, field)| (key.to_string(), from_field(field)))
+                    .collect();
+                each(Value::Object(row))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Dates and times, which JSON has no type for, become strings
+fn from_toml(value: toml::Value) -> Value
+{
+    match value
+    {
+        toml::Value::String(string) => Value::String(string),
+        toml::Value::Integer(int) => Value::from(int),
+        toml::Value::Float(float) => Value::from(float),
+        toml::Value::Boolean(bool) => Value::Bool(bool),
+        toml::Value::Datetime(datetime) => Value::String(datetime.to_string()),
+        toml::Value::Array(array) =>
+        {
+            Value::Array(array.into_iter().map(from_toml).collect())
+        }
+        toml::Value::Table(table) => Value::Object(
+            table
+                .into_iter()
+                .map(|(key, value)| (key, from_toml(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// A field that reads back exactly as a JSON number is one, anything else
+/// stays a string so that `007` keeps its zeros
+fn from_field(field: &str) -> Value
+{
+    match serde_json::from_str::<serde_json::Number>(field)
+    {
+        Ok(number) if number.to_string() == field => Value::Number(number),
+        _ => Value::String(field.to_string()),
+    }
+}
+
+/// Writes one result in `output.format`, other than JSON which
+/// `write_value` handles itself
+pub fn write(
+    out: &mut impl Write,
+    value: &Value,
+    output: &Output,
+) -> Result<(), PqError>
+{
+    let format = output.format;
+    let unsupported = |err: &dyn std::fmt::Display| {
+        PqError::Format(format!("cannot write {format}: {err}"))
+    };
+
+    match format
+    {
+        Format::Json | Format::Ndjson =>
+        {
+            unreachable!("JSON output goes through `write_value`")
+        }
+        Format::Yaml =>
+        {
+            // Every result after the first starts a new document
+            if output.started.replace(true)
+            {
+                out.write_all(b"---\n")?;
+            }
+            let yaml = serde_yaml::to_string(value)
+                .map_err(|err| unsupported(&err))?;
+            out.write_all(yaml.as_bytes())?;
+        }
+        Format::Toml =>
+        {
+            // A TOML file is a single table, with no way to hold a second
+            if output.started.replace(true)
+            {
+                return Err(unsupported(
+                    &"the query gave more than one result",
+                ));
+            }
+            if has_null(value)
+            {
+                return Err(unsupported(&"TOML has no null"));
+            }
+            if !value.is_object()
+            {
+                return Err(unsupported(&format!(
+                    "the result has to be an object, not {}",
+                    type_name(value)
+                )));
+            }
+            let toml =
+                toml::to_string(value).map_err(|err| unsupported(&err))?;
+            out.write_all(toml.as_bytes())?;
+        }
+        Format::Csv =>
+        {
+            let mut csv = csv::Writer::from_writer(out);
+            let row: Vec<String> = match value
+            {
+                Value::Object(object) =>
+                {
+                    let mut header = output.header.borrow_mut();
+                    let header = header.get_or_insert_with(|| {
+                        object.keys().cloned().collect()
+                    });
+                    if !output.started.replace(true)
+                    {
+                        csv.write_record(header.iter())
+                            .map_err(|err| unsupported(&err))?;
+                    }
+                    if let Some(key) =
+                        object.keys().find(|key| !header.contains(key))
+                    {
+                        return Err(unsupported(&format!(
+                            "key `{key}` is not in the header, which comes \
+                             from the first object"
+                        )));
+                    }
+                    header
+                        .iter()
+                        .map(|key| {
+                            to_field(object.get(key).unwrap_or(&Value::Null))
+                        })
+                        .collect()
+                }
+                Value::Array(array) => array.iter().map(to_field).collect(),
+                value => vec![to_field(value)],
+            };
+            csv.write_record(row).map_err(|err| unsupported(&err))?;
+            csv.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn has_null(value: &Value) -> bool
+{
+    match value
+    {
+        Value::Null => true,
+        Value::Array(array) => array.iter().any(has_null),
+        Value::Object(object) => object.values().any(has_null),
+        _ => false,
+    }
+}
+
+/// Strings go in as they are, `null` as an empty field and anything else as
+/// JSON
+fn to_field(value: &Value) -> String
+{
+    match value
+    {
+        Value::Null => String::new(),
+        Value::String(string) => string.clone(),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn from_field_reads_numbers()
+    {
+        assert_eq!(from_field("42"), json!(42));
+        assert_eq!(from_field("-1.5"), json!(-1.5));
+    }
+
+    #[test]
+    fn from_field_keeps_anything_else_a_string()
+    {
+        assert_eq!(from_field("007"), json!("007"));
+        assert_eq!(from_field("1.50"), json!("1.50"));
+        assert_eq!(from_field("1e3"), json!("1e3"));
+        assert_eq!(from_field(" 1"), json!(" 1"));
+        assert_eq!(from_field("true"), json!("true"));
+        assert_eq!(from_field(""), json!(""));
+    }
+}
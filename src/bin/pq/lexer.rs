@@ -0,0 +1,410 @@
+//! Splits a query into tokens. Embedded Python is left to Python's own
+//! tokenizer, which is what knows that the `)` in `(f(")"))` is in a string.
+
+use rustpython_parser::lexer::{lex, LexicalErrorType};
+use rustpython_parser::{ast, Mode, Parse, Tok};
+
+use crate::{PqError, QueryError, QueryErrorKind, Span};
+
+/// How deep pipelines can nest inside brackets, arguments and other
+/// pipelines. Kept well under the depth `evaluate` allows, so that no query
+/// is refused for its shape alone.
+pub const MAX_DEPTH: usize = 128;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token
+{
+    Dot,
+    DotDot,
+    Star,
+    Comma,
+    Colon,
+    Semicolon,
+    Question,
+    Minus,
+    LBracket,
+    RBracket,
+    LBrace,
+    RBrace,
+    LParen,
+    RParen,
+
+    /// `=`
+    Assign,
+
+    /// `|=`
+    Update,
+
+    /// `//`
+    Alternative,
+
+    Ident(String),
+
+    /// A JSON string literal, already unescaped
+    Str(String),
+
+    Int(isize),
+
+    /// `$name`
+    Variable(String),
+
+    End,
+}
+
+/// Reads tokens on demand, so the parser can decide where Python starts
+#[derive(Clone)]
+pub struct Lexer<'a>
+{
+    source: &'a str,
+
+    /// Byte offset of the next token
+    offset: usize,
+
+    /// How many pipelines the parser is inside of
+    depth: usize,
+}
+
+impl<'a> Lexer<'a>
+{
+    pub fn new(source: &'a str) -> Self
+    {
+        Self { source, offset: 0, depth: 0 }
+    }
+
+    pub fn error(
+        &self,
+        offset: usize,
+        kind: QueryErrorKind,
+        expected: &[&'static str],
+    ) -> PqError
+    {
+        PqError::Query(QueryError { kind, offset, expected: expected.to_vec() })
+    }
+
+    /// Starts a pipeline inside the current one, or fails at `offset` once
+    /// they are `MAX_DEPTH` deep, before the parser runs out of stack
+    pub fn enter(&mut self, offset: usize) -> Result<(), PqError>
+    {
+        if self.depth >= MAX_DEPTH
+        {
+            return Err(self.error(offset, QueryErrorKind::TooDeep, &[]));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    pub fn leave(&mut self)
+    {
+        self.depth -= 1;
+    }
+
+    /// An error for whatever token is at `offset`
+    pub fn unexpected(
+        &self,
+        offset: usize,
+        expected: &[&'static str],
+    ) -> PqError
+    {
+        let kind = match self.source[offset ..].chars().next()
+        {
+            Some(c) => QueryErrorKind::UnexpectedChar(c),
+            None => QueryErrorKind::UnexpectedEnd,
+        };
+        self.error(offset, kind, expected)
+    }
+
+    /// Whether the next token starts with one of `chars`
+    pub fn at(&self, chars: &[char]) -> bool
+    {
+        self.source[self.offset ..].trim_start().starts_with(chars)
+    }
+
+    fn skip_whitespace(&mut self)
+    {
+        let rest = &self.source[self.offset ..];
+        self.offset += rest.len() - rest.trim_start().len();
+    }
+
+    pub fn peek(&self) -> Result<(Token, Span), PqError>
+    {
+        self.clone().next()
+    }
+
+    /// Whether the next token starts right where the previous one ended
+    pub fn adjacent(&self) -> bool
+    {
+        !self.source[self.offset ..].starts_with(char::is_whitespace)
+    }
+
+    pub fn next(&mut self) -> Result<(Token, Span), PqError>
+    {
+        self.skip_whitespace();
+
+        let start = self.offset;
+        let rest = &self.source[start ..];
+        let Some(c) = rest.chars().next()
+        else
+        {
+            return Ok((Token::End, start .. start));
+        };
+
+        let (token, len) = match c
+        {
+            '.' if rest.starts_with("..") => (Token::DotDot, 2),
+            '.' => (Token::Dot, 1),
+            '*' => (Token::Star, 1),
+            ',' => (Token::Comma, 1),
+            ':' => (Token::Colon, 1),
+            ';' => (Token::Semicolon, 1),
+            '?' => (Token::Question, 1),
+            '-' => (Token::Minus, 1),
+            '[' => (Token::LBracket, 1),
+            ']' => (Token::RBracket, 1),
+            '{' => (Token::LBrace, 1),
+            '}' => (Token::RBrace, 1),
+            '(' => (Token::LParen, 1),
+            ')' => (Token::RParen, 1),
+            '=' => (Token::Assign, 1),
+            '|' if rest.starts_with("|=") => (Token::Update, 2),
+            '/' if rest.starts_with("//") => (Token::Alternative, 2),
+            '"' =>
+            {
+                let len = self.string_len(start)?;
+                let key =
+                    serde_json::from_str(&rest[.. len]).map_err(|_| {
+                        self.error(start, QueryErrorKind::InvalidKey, &[
+                            "JSON string",
+                        ])
+                    })?;
+                (Token::Str(key), len)
+            }
+            '$' =>
+            {
+                let len = ident_len(&rest[1 ..]);
+                if len == 0
+                {
+                    return Err(self.error(
+                        start + 1,
+                        QueryErrorKind::InvalidKey,
+                        &["variable name"],
+                    ));
+                }
+                (Token::Variable(rest[1 ..= len].to_string()), len + 1)
+            }
+            '0' ..= '9' =>
+            {
+                let len = rest.find(|c: char| !c.is_ascii_digit());
+                let len = len.unwrap_or(rest.len());
+                let int = rest[.. len].parse().map_err(|_| {
+                    self.error(start, QueryErrorKind::InvalidIndex, &["index"])
+                })?;
+                (Token::Int(int), len)
+            }
+            c if c.is_alphabetic() || c == '_' =>
+            {
+                let len = ident_len(rest);
+                (Token::Ident(rest[.. len].to_string()), len)
+            }
+            c =>
+            {
+                return Err(self.error(
+                    start,
+                    QueryErrorKind::UnexpectedChar(c),
+                    &["key", "`\"`", "`.`", "`[`", "`{`", "`(`", "`?(`", "`$`"],
+                ));
+            }
+        };
+
+        self.offset += len;
+        Ok((token, start .. self.offset))
+    }
+
+    /// Byte length of the JSON string literal starting at `start`
+    fn string_len(&self, start: usize) -> Result<usize, PqError>
+    {
+        let mut escaped = false;
+        for (i, c) in self.source[start + 1 ..].char_indices()
+        {
+            match c
+            {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => return Ok(i + 2),
+                _ => (),
+            }
+        }
+
+        Err(self
+            .error(self.source.len(), QueryErrorKind::UnexpectedEnd, &["`\"`"]))
+    }
+
+    /// A parenthesized Python expression, parentheses included
+    pub fn python_parenthesized(&mut self) -> Result<(String, Span), PqError>
+    {
+        self.skip_whitespace();
+        if !self.source[self.offset ..].starts_with('(')
+        {
+            return Err(self.unexpected(self.offset, &["`(`"]));
+        }
+
+        self.python(&[])
+    }
+
+    /// A Python expression that runs until one of `stops` outside of any
+    /// brackets, as in the `"a" + "b"` of `{"a" + "b": c}`
+    pub fn python_until(
+        &mut self,
+        stops: &[Tok],
+    ) -> Result<(String, Span), PqError>
+    {
+        self.skip_whitespace();
+        self.python(stops)
+    }
+
+    /// Without `stops`, the expression ends where its first bracket closes
+    fn python(&mut self, stops: &[Tok]) -> Result<(String, Span), PqError>
+    {
+        let start = self.offset;
+        let mut depth = 0usize;
+        let mut end = None;
+        let mut tokens =
+            lex(&self.source[start ..], Mode::Expression).peekable();
+        while let Some(result) = tokens.next()
+        {
+            let (tok, range) = match result
+            {
+                Ok(token) => token,
+                // Python's tokenizer refuses a bracket it never saw open,
+                // which is exactly where an expression inside `{...}` ends
+                Err(err)
+                    if depth == 0
+                        && matches!(
+                            err.error,
+                            LexicalErrorType::NestingError
+                        ) =>
+                {
+                    end = Some(usize::from(err.location) - 1);
+                    break;
+                }
+                Err(err) if matches!(err.error, LexicalErrorType::Eof) => break,
+                Err(err) =>
+                {
+                    let offset = start + usize::from(err.location);
+                    return Err(self.error(
+                        offset,
+                        QueryErrorKind::InvalidPython,
+                        &["a Python expression"],
+                    ));
+                }
+            };
+            let (tok_start, tok_end) =
+                (usize::from(range.start()), usize::from(range.end()));
+
+            if depth == 0 && stops.contains(&tok)
+            {
+                end = Some(tok_start);
+                break;
+            }
+
+            match tok
+            {
+                Tok::Lpar | Tok::Lsqb | Tok::Lbrace => depth += 1,
+                Tok::Rpar | Tok::Rsqb | Tok::Rbrace => depth -= 1,
+                Tok::Newline | Tok::EndOfFile => break,
+                _ => (),
+            }
+
+            // `(f)(x)` goes on to call whatever `(f)` evaluates to
+            let called = matches!(
+                tokens.peek(),
+                Some(Ok((Tok::Lpar, next))) if next.start() == range.end()
+            );
+            if depth == 0 && stops.is_empty() && !called
+            {
+                end = Some(tok_end);
+                break;
+            }
+        }
+
+        let Some(end) = end.filter(|end| *end > 0)
+        else
+        {
+            let expected: &[_] =
+                if stops.is_empty() { &["`)`"] } else { &["`,`", "`}`"] };
+            return Err(self.error(
+                self.source.len(),
+                QueryErrorKind::UnexpectedEnd,
+                expected,
+            ));
+        };
+
+        let source = self.source[start .. start + end].trim_end();
+        if let Err(err) = ast::Expr::parse(source, "<query>")
+        {
+            let offset = start + usize::from(err.offset).min(source.len());
+            return Err(self.error(offset, QueryErrorKind::InvalidPython, &[
+                "a Python expression",
+            ]));
+        }
+
+        self.offset = start + source.len();
+        Ok((source.to_string(), start .. self.offset))
+    }
+}
+
+fn ident_len(source: &str) -> usize
+{
+    match source.chars().next()
+    {
+        Some(c) if c.is_alphabetic() || c == '_' => source
+            .find(|c: char| !c.is_alphanumeric() && c != '_')
+            .unwrap_or(source.len()),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn parenthesized(query: &str) -> String
+    {
+        Lexer::new(query).python_parenthesized().unwrap().0
+    }
+
+    fn until(query: &str) -> String
+    {
+        let stops = [Tok::Colon, Tok::Comma, Tok::Rbrace];
+        Lexer::new(query).python_until(&stops).unwrap().0
+    }
+
+    #[test]
+    fn python_goes_on_to_a_call()
+    {
+        assert_eq!(parenthesized("(a)(b).c"), "(a)(b)");
+        assert_eq!(parenthesized("(a) (b)"), "(a)");
+    }
+
+    #[test]
+    fn python_ignores_brackets_in_strings()
+    {
+        assert_eq!(parenthesized(r#"(f(")")) x"#), r#"(f(")"))"#);
+        assert_eq!(parenthesized(r#"("(" + ']')"#), r#"("(" + ']')"#);
+    }
+
+    #[test]
+    fn python_runs_until_a_stop_outside_brackets()
+    {
+        assert_eq!(until(r#""x" + "y": c}"#), r#""x" + "y""#);
+        assert_eq!(until(r#"f(a, b), c}"#), "f(a, b)");
+        assert_eq!(until(r#""x" + "y"}"#), r#""x" + "y""#);
+    }
+
+    #[test]
+    fn python_fails_without_an_end()
+    {
+        assert!(Lexer::new("(a + b").python_parenthesized().is_err());
+        assert!(Lexer::new("(a +)").python_parenthesized().is_err());
+    }
+}
@@ -0,0 +1,1663 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io::{IsTerminal, Read, Write};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use ariadne::{Color, Config, Label, Report, ReportKind, Source};
+use loveutils::pipeline::PipelineInvocation;
+use pyo3::prelude::*;
+use serde::Serialize;
+use serde_json::ser::{CompactFormatter, Formatter, PrettyFormatter};
+
+type Span = Range<usize>;
+
+const USAGE: &str = r#"
+pq - Query JSON using a DSL that embeds Python expressions
+Usage: pq [options] <expr> [file...]
+Example: echo '{"name":"allovelle"}' | pq 'name.(_.upper())'
+Assignment: a.b = (1)   a.b |= (_ + 1)   del(a.c)
+Functions of your own: def name($a, $b): query; name(1st, 2nd)
+Errors: a.b? skips a step that fails, a.b // (0) falls back when a.b gives
+    nothing but null or false
+Functions: length() keys() values() to_entries() from_entries() unique()
+    min() max() add() flatten([depth]) sort_by(query) group_by(query)
+    paths() leaf_paths() getpath(path) setpath(path, value)
+Options:
+    -c, --compact         Print each result on a single line
+    --pretty[=indent]     Indent results by `indent` spaces (default: 2)
+    -r, --raw             Print strings without quotes
+    -S, --sort-keys       Sort the keys of every object
+    -j, --join-output     Like --raw, but without newlines between results
+    --color[=when]        Highlight output: auto (default), always or never
+    --with-path           Print each result as [path, value], where path leads
+                          from the input to the result, or null if computed
+    --from format         Read json (default), ndjson, yaml, toml or csv
+    --to format           Write results as json (default), ndjson, yaml, toml
+                          or csv. TOML takes a single object, and CSV objects
+                          can only have the first object's keys
+    -n, --null-input      Run the query once on `null` instead of any input
+    -f file               Load definitions from a .pq file, or a .py module
+                          that Python expressions can use by its name
+    --library dir         Load every .pq and .py file in `dir` like -f
+    --arg name value      Make the string `value` available as `$name`
+    --argjson name json   Make the JSON value `json` available as `$name`
+    --strict              Fail on missing keys and indices instead of null
+    --explain             Show how the query is parsed and what each step does
+    --trace               Print how the query is parsed and evaluated to stderr
+"#;
+
+/// How many pipelines can be evaluated inside one another, counting every
+/// function call, branch and argument on the way. Each one can take well over
+/// 16KiB of stack in a debug build, and this keeps them within the 8MiB of
+/// the main thread.
+const MAX_DEPTH: usize = 256;
+
+/// Set once from `--trace` before any parsing happens
+static TRACE: AtomicBool = AtomicBool::new(false);
+
+/// Like `eprintln!`, but only when running with `--trace`
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if $crate::TRACE.load(std::sync::atomic::Ordering::Relaxed)
+        {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+mod ast;
+mod builtins;
+mod explain;
+mod format;
+mod lexer;
+mod parser;
+mod path;
+mod python;
+
+use ast::{
+    Function, ObjectEntry, ObjectKey, ObjectValue, Pipeline, Query, Spanned,
+};
+use explain::Shape;
+use format::Format;
+use parser::{check_calls, parse_library, parse_queries};
+use python::{eval_python, from_python, Interpreter, ObjectLocals};
+
+// Highlighting for keys, strings, numbers and `null`/`true`/`false`
+const BLUE: &str = "\x1b[34;1m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RED: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Debug)]
+pub enum PqError
+{
+    Io(std::io::Error),
+
+    /// Malformed JSON in the input stream
+    Input(serde_json::Error),
+
+    /// A result that could not be written out as JSON
+    Json(serde_json::Error),
+    Query(QueryError),
+    Python
+    {
+        err: pyo3::PyErr,
+
+        /// The expression that raised, once known
+        span: Option<Span>,
+    },
+    Type
+    {
+        message: String,
+
+        /// The query step that was given the wrong kind of value
+        span: Span,
+    },
+
+    /// Input or output in another format than JSON that did not convert
+    Format(String),
+}
+
+/// Where and why a query failed to parse
+#[derive(Debug)]
+pub struct QueryError
+{
+    pub kind: QueryErrorKind,
+
+    /// Byte offset into the query string
+    pub offset: usize,
+
+    /// Descriptions of the tokens that would have been accepted at `offset`
+    pub expected: Vec<&'static str>,
+}
+
+#[derive(Debug)]
+pub enum QueryErrorKind
+{
+    UnexpectedChar(char),
+    UnexpectedEnd,
+    InvalidIndex,
+    InvalidKey,
+    InvalidPython,
+    UnknownFunction(String),
+
+    /// A `def` with the name of a built-in function, which would never be
+    /// called
+    RedefinedBuiltin(String),
+
+    /// Brackets, arguments and the like inside one another past
+    /// `lexer::MAX_DEPTH`
+    TooDeep,
+
+    /// A step on the left of `=` or in `del(...)` that is not a location
+    NotAPath,
+
+    WrongArguments
+    {
+        name: String,
+        expected: usize,
+    },
+}
+
+impl PqError
+{
+    /// Points a Python error that does not know its expression yet at `span`
+    fn with_span(self, span: &Span) -> Self
+    {
+        match self
+        {
+            Self::Python { err, span: None } =>
+            {
+                Self::Python { err, span: Some(span.clone()) }
+            }
+            err => err,
+        }
+    }
+}
+
+impl std::fmt::Display for PqError
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            Self::Io(err) => write!(f, "{err}"),
+            Self::Input(err) => write!(f, "invalid JSON input: {err}"),
+            Self::Json(err) => write!(f, "cannot write JSON: {err}"),
+            Self::Query(err) => write!(f, "{err}"),
+            Self::Python { err, .. } => write!(f, "Python error: {err}"),
+            Self::Type { message, .. } => write!(f, "{message}"),
+            Self::Format(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::fmt::Display for QueryError
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        write!(f, "{} at offset {}", self.kind, self.offset)?;
+
+        if !self.expected.is_empty()
+        {
+            write!(f, ", expected {}", self.expected.join(" or "))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for QueryErrorKind
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            Self::UnexpectedChar(c) => write!(f, "unexpected `{c}`"),
+            Self::UnexpectedEnd => write!(f, "unexpected end of query"),
+            Self::InvalidIndex => write!(f, "invalid index"),
+            Self::InvalidKey => write!(f, "invalid key"),
+            Self::InvalidPython => write!(f, "invalid Python expression"),
+            Self::UnknownFunction(name) =>
+            {
+                write!(f, "unknown function `{name}`")
+            }
+            Self::RedefinedBuiltin(name) =>
+            {
+                write!(f, "`{name}` is a built-in function")
+            }
+            Self::TooDeep => write!(f, "query nests too deep"),
+            Self::NotAPath => write!(f, "cannot assign to this step"),
+            Self::WrongArguments { name, expected: 1 } =>
+            {
+                write!(f, "`{name}` takes 1 argument")
+            }
+            Self::WrongArguments { name, expected } =>
+            {
+                write!(f, "`{name}` takes {expected} arguments")
+            }
+        }
+    }
+}
+
+#[rustfmt::skip]
+impl From<std::io::Error> for PqError
+{
+    fn from(value: std::io::Error) -> Self { Self::Io(value) }
+}
+
+/// Failing to write is an I/O error like any other, even from inside
+/// serde_json, so that `pq ... | head` still exits quietly
+impl From<serde_json::Error> for PqError
+{
+    fn from(value: serde_json::Error) -> Self
+    {
+        if value.is_io()
+        {
+            Self::Io(value.into())
+        }
+        else
+        {
+            Self::Json(value)
+        }
+    }
+}
+
+#[rustfmt::skip]
+impl From<PyErr> for PqError
+{
+    fn from(value: PyErr) -> Self { Self::Python { err: value, span: None } }
+}
+
+/// How results are written to stdout
+struct Output
+{
+    /// Spaces per level of nesting, or `None` to print on one line
+    indent: Option<usize>,
+    raw: bool,
+    sort_keys: bool,
+    join: bool,
+    color: bool,
+
+    /// Print `[path, value]` pairs, with the path from the input's root to
+    /// each result or `null` once a step computed something new
+    with_path: bool,
+
+    format: Format,
+
+    /// Whether anything was written yet, which YAML separates documents by,
+    /// TOML refuses a second result over and CSV writes its header before
+    started: Cell<bool>,
+
+    /// The columns of CSV output, from the keys of the first object
+    header: RefCell<Option<Vec<String>>>,
+}
+
+impl Default for Output
+{
+    fn default() -> Self
+    {
+        Self {
+            indent: Some(2),
+            raw: false,
+            sort_keys: false,
+            join: false,
+            color: false,
+            with_path: false,
+            format: Format::Json,
+            started: Cell::new(false),
+            header: RefCell::new(None),
+        }
+    }
+}
+
+/// What a query can see besides the value it runs on
+struct Env
+{
+    /// The file being read, `__file__` in Python, or `None` for stdin
+    file: Option<String>,
+
+    /// From `--arg` and `--argjson`, `$name` in queries and `name` in Python
+    args: serde_json::Map<String, serde_json::Value>,
+
+    python: Interpreter,
+
+    /// From `--strict`, missing keys and indices are errors instead of `null`
+    strict: bool,
+
+    /// Defined by libraries and then the query, which wins on a clash
+    functions: HashMap<String, Function>,
+
+    /// The arguments of every function call in progress, innermost last
+    scopes: RefCell<Vec<serde_json::Map<String, serde_json::Value>>>,
+
+    /// How many pipelines are being evaluated inside one another
+    depth: Cell<usize>,
+}
+
+impl Env
+{
+    fn variable(
+        &self,
+        name: &str,
+        span: &Span,
+    ) -> Result<serde_json::Value, PqError>
+    {
+        let scopes = self.scopes.borrow();
+        let scope = scopes.last().and_then(|scope| scope.get(name));
+        let value = scope.or_else(|| self.args.get(name));
+        value.cloned().ok_or_else(|| PqError::Type {
+            message: format!("`${name}` is not defined, pass it with --arg"),
+            span: span.clone(),
+        })
+    }
+}
+
+/// `--color=when`, decided once all arguments have been read
+enum ColorChoice
+{
+    Auto,
+    Always,
+    Never,
+}
+
+fn main()
+{
+    let mut query = None;
+    let mut output = Output::default();
+    let mut color = ColorChoice::Auto;
+    let mut null_input = false;
+    let mut from = Format::Json;
+    let mut explain = false;
+    let mut files = vec![];
+    let mut libraries = vec![];
+    let mut env = Env {
+        file: None,
+        args: serde_json::Map::new(),
+        python: Interpreter::default(),
+        strict: false,
+        functions: HashMap::new(),
+        scopes: RefCell::default(),
+        depth: Cell::new(0),
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next()
+    {
+        match arg.as_str()
+        {
+            "--trace" => TRACE.store(true, Ordering::Relaxed),
+            "--explain" => explain = true,
+            "--strict" => env.strict = true,
+            "-c" | "--compact" => output.indent = None,
+            "--pretty" => output.indent = Some(2),
+            "-r" | "--raw" => output.raw = true,
+            "-S" | "--sort-keys" => output.sort_keys = true,
+            "--with-path" => output.with_path = true,
+            "-j" | "--join-output" =>
+            {
+                output.raw = true;
+                output.join = true;
+            }
+            _ if arg.starts_with("--pretty=") =>
+            {
+                match arg["--pretty=".len() ..].parse()
+                {
+                    Ok(indent) => output.indent = Some(indent),
+                    Err(_) => usage(),
+                }
+            }
+            "--color" | "--color=always" => color = ColorChoice::Always,
+            "--color=auto" => color = ColorChoice::Auto,
+            "--color=never" => color = ColorChoice::Never,
+            "-n" | "--null-input" => null_input = true,
+            "--from" | "--to" =>
+            {
+                let name = args.next().unwrap_or_else(|| usage());
+                let format = Format::parse(&name).unwrap_or_else(|| {
+                    eprintln!(
+                        "pq: {arg} {name}: expected json, ndjson, yaml, toml \
+                         or csv"
+                    );
+                    std::process::exit(2);
+                });
+                match arg.as_str()
+                {
+                    "--from" => from = format,
+                    _ => output.format = format,
+                }
+            }
+            "-f" => libraries.push(args.next().unwrap_or_else(|| usage())),
+            "--library" =>
+            {
+                let dir = args.next().unwrap_or_else(|| usage());
+                libraries.extend(library_dir(&dir));
+            }
+            "--arg" | "--argjson" =>
+            {
+                let (Some(name), Some(value)) = (args.next(), args.next())
+                else
+                {
+                    usage();
+                };
+                let value = match arg.as_str()
+                {
+                    "--arg" => serde_json::Value::String(value),
+                    _ => serde_json::from_str(&value).unwrap_or_else(|err| {
+                        eprintln!("pq: --argjson {name}: {err}");
+                        std::process::exit(2);
+                    }),
+                };
+                env.args.insert(name, value);
+            }
+            _ if arg.starts_with('-') => usage(),
+            _ if query.is_none() => query = Some(arg),
+            _ => files.push(arg),
+        }
+    }
+
+    let Some(query) = query
+    else
+    {
+        println!("{}", USAGE.trim());
+        return;
+    };
+
+    if output.format == Format::Ndjson
+    {
+        output.indent = None;
+    }
+
+    // https://no-color.org: any non-empty value turns automatic color off
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    output.color = match color
+    {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto =>
+        {
+            !no_color && PipelineInvocation::get().stdout_is_terminal()
+        }
+    };
+
+    let mut sources = HashMap::new();
+    for path in libraries
+    {
+        if path.ends_with(".py")
+        {
+            Python::with_gil(|py| env.python.import(py, &path))
+                .unwrap_or_else(|err| fail(&err.into(), &query, None));
+            continue;
+        }
+
+        let text = std::fs::read_to_string(&path).unwrap_or_else(|err| {
+            let err = std::io::Error::new(err.kind(), format!("{path}: {err}"));
+            fail(&PqError::Io(err), &query, None)
+        });
+        let functions = parse_library(&text, &path)
+            .unwrap_or_else(|err| fail(&err, &query, Some((&path, &text))));
+        for function in functions
+        {
+            env.functions.insert(function.name.clone(), function);
+        }
+        sources.insert(path, text);
+    }
+
+    let (functions, queries) =
+        parse_queries(&query).unwrap_or_else(|err| fail(&err, &query, None));
+    trace!("Queries: {queries:?}");
+
+    for function in functions
+    {
+        env.functions.insert(function.name.clone(), function);
+    }
+    for function in env.functions.values()
+    {
+        let source = function.file.as_ref().map(|file| (file, &sources[file]));
+        check_calls(&function.body, &env.functions).unwrap_or_else(|err| {
+            let input =
+                source.map(|(file, text)| (file.as_str(), text.as_str()));
+            fail(&err, &query, input)
+        });
+    }
+    check_calls(&queries, &env.functions)
+        .unwrap_or_else(|err| fail(&err, &query, None));
+
+    if explain
+    {
+        let input = if null_input { Shape::Null } else { Shape::Any };
+        print!("{}", explain::explain(&queries, input));
+        return;
+    }
+
+    if null_input
+    {
+        process_queries(&env, serde_json::Value::Null, &queries, &output)
+            .unwrap_or_else(|err| fail(&err, &query, None));
+        return;
+    }
+
+    if files.is_empty()
+    {
+        let stdin = std::io::stdin().lock();
+        process_input(&env, stdin, from, &query, &queries, &output);
+    }
+
+    for path in files
+    {
+        let file = std::fs::File::open(&path).unwrap_or_else(|err| {
+            let err = std::io::Error::new(err.kind(), format!("{path}: {err}"));
+            fail(&PqError::Io(err), &query, None)
+        });
+        env.file = Some(path);
+        let reader = std::io::BufReader::new(file);
+        process_input(&env, reader, from, &query, &queries, &output);
+    }
+}
+
+/// Runs the query over one input, exiting with a report on the first error
+fn process_input(
+    env: &Env,
+    reader: impl Read,
+    format: Format,
+    query: &str,
+    queries: &Pipeline,
+    output: &Output,
+)
+{
+    if !matches!(format, Format::Json | Format::Ndjson)
+    {
+        format::read(format, reader, |json| {
+            process_queries(env, json, queries, output)
+        })
+        .unwrap_or_else(|err| fail(&err, query, None));
+        return;
+    }
+
+    let mut reader =
+        LineTracker { inner: reader, line: 1, text: vec![], previous: vec![] };
+    match process_stream(env, &mut reader, queries, output)
+    {
+        Ok(()) => (),
+        Err(PqError::Input(err)) =>
+        {
+            let input = reader.context(err.line());
+            let name = env.file.as_deref().unwrap_or("input");
+            fail(&PqError::Input(err), query, Some((name, &input)));
+        }
+        Err(err) => fail(&err, query, None),
+    }
+}
+
+/// The `.pq` and `.py` files in `dir`, in a stable order
+fn library_dir(dir: &str) -> Vec<String>
+{
+    let entries = std::fs::read_dir(dir).unwrap_or_else(|err| {
+        eprintln!("pq: --library {dir}: {err}");
+        std::process::exit(2);
+    });
+
+    let mut files: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension().is_some_and(|ext| ext == "pq" || ext == "py")
+        })
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+    files.sort();
+
+    files
+}
+
+/// Prints `USAGE` for arguments that do not make sense and exits
+fn usage() -> !
+{
+    eprintln!("{}", USAGE.trim());
+    std::process::exit(2);
+}
+
+/// Keeps only the line currently being read and the one before it, so a JSON
+/// error can be shown in context without holding on to the whole stream.
+/// serde_json can read past the end of the line an error is on before it
+/// reports it.
+struct LineTracker<R>
+{
+    inner: R,
+    line: usize,
+    text: Vec<u8>,
+    previous: Vec<u8>,
+}
+
+impl<R> LineTracker<R>
+{
+    /// Input to show an error on `line` in, or on the current line when that
+    /// one is no longer kept
+    fn context(&self, line: usize) -> String
+    {
+        let (line, text) = if line + 1 == self.line
+        {
+            (line, &self.previous)
+        }
+        else
+        {
+            (self.line, &self.text)
+        };
+
+        // Blank lines keep ariadne's line numbers true to the input
+        let mut input = "\n".repeat(line - 1);
+        input.push_str(&String::from_utf8_lossy(text));
+        input
+    }
+}
+
+impl<R: Read> Read for LineTracker<R>
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize>
+    {
+        let read = self.inner.read(buf)?;
+        for &byte in &buf[.. read]
+        {
+            if byte == b'\n'
+            {
+                self.line += 1;
+                self.previous = std::mem::take(&mut self.text);
+            }
+            else
+            {
+                self.text.push(byte);
+            }
+        }
+        Ok(read)
+    }
+}
+
+/// Runs the query over every JSON value in `reader` as soon as it is parsed,
+/// whether the values are newline delimited or simply concatenated
+fn process_stream(
+    env: &Env,
+    reader: impl Read,
+    queries: &Pipeline,
+    output: &Output,
+) -> Result<(), PqError>
+{
+    let stream = serde_json::Deserializer::from_reader(reader);
+    for json in stream.into_iter::<serde_json::Value>()
+    {
+        let json = json.map_err(PqError::Input)?;
+        trace!("{json}");
+
+        process_queries(env, json, queries, output)?;
+    }
+
+    Ok(())
+}
+
+/// Reports `err` and exits, with 2 for a bad query and 1 for anything else
+fn fail(err: &PqError, query: &str, input: Option<(&str, &str)>) -> !
+{
+    // Whoever reads our output has stopped, e.g. `pq ... | head`
+    if let PqError::Io(err) = err
+    {
+        if err.kind() == std::io::ErrorKind::BrokenPipe
+        {
+            std::process::exit(0);
+        }
+    }
+
+    report(err, query, input);
+    std::process::exit(match err
+    {
+        PqError::Query(..) => 2,
+        _ => 1,
+    });
+}
+
+/// Prints `err` to stderr as a diagnostic pointing into the query, or into
+/// the named `input` for JSON or a library file that failed to parse
+fn report(err: &PqError, query: &str, input: Option<(&str, &str)>)
+{
+    let (id, source, span, message, label) = match (err, input)
+    {
+        (PqError::Query(err), input) =>
+        {
+            let (id, query) = input.unwrap_or(("query", query));
+            let width = query[err.offset ..].chars().next().map(char::len_utf8);
+            let label = if err.expected.is_empty()
+            {
+                err.kind.to_string()
+            }
+            else
+            {
+                format!("expected {}", err.expected.join(" or "))
+            };
+            let span = err.offset .. err.offset + width.unwrap_or(0);
+            (id, query, span, err.kind.to_string(), label)
+        }
+        (PqError::Type { message, span }, _) =>
+        {
+            let label = "this step cannot handle that value".to_string();
+            ("query", query, span.clone(), message.clone(), label)
+        }
+        (PqError::Python { err, span: Some(span) }, _) =>
+        {
+            let message = "Python expression raised an exception".to_string();
+            ("query", query, span.clone(), message, err.to_string())
+        }
+        (PqError::Input(err), Some((name, input))) =>
+        {
+            // serde_json counts lines and columns from 1, columns in bytes
+            let line_start: usize = input
+                .split_inclusive('\n')
+                .take(err.line().saturating_sub(1))
+                .map(str::len)
+                .sum();
+            let offset =
+                (line_start + err.column().saturating_sub(1)).min(input.len());
+            let width = input[offset ..].chars().next().map(char::len_utf8);
+            let message = err.to_string();
+            let label = message
+                .strip_suffix(&format!(
+                    " at line {} column {}",
+                    err.line(),
+                    err.column()
+                ))
+                .unwrap_or(&message)
+                .to_string();
+            let span = offset .. offset + width.unwrap_or(0);
+            (name, input, span, "invalid JSON input".to_string(), label)
+        }
+        _ =>
+        {
+            eprintln!("pq: {err}");
+            return;
+        }
+    };
+
+    // Spans are byte offsets while ariadne counts in chars
+    let chars = |offset: usize| source[.. offset].chars().count();
+    let span = chars(span.start) .. chars(span.end);
+
+    let kind = ReportKind::Error;
+    let config = Config::default().with_color(std::io::stderr().is_terminal());
+    Report::build(kind, id, span.start)
+        .with_config(config)
+        .with_message(message)
+        .with_label(
+            Label::new((id, span)).with_message(label).with_color(Color::Red),
+        )
+        .finish()
+        .eprint((id, Source::from(source)))
+        .ok();
+}
+
+fn type_name(value: &serde_json::Value) -> &'static str
+{
+    match value
+    {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "a boolean",
+        serde_json::Value::Number(_) => "a number",
+        serde_json::Value::String(_) => "a string",
+        serde_json::Value::Array(_) => "an array",
+        serde_json::Value::Object(_) => "an object",
+    }
+}
+
+/// The indices `[start:end:step]` picks out of `len` items, following
+/// Python's rules for clamping negative and out of range bounds
+fn slice_indices(
+    len: usize,
+    start: Option<isize>,
+    end: Option<isize>,
+    step: Option<isize>,
+) -> Option<impl Iterator<Item = usize>>
+{
+    let len = len as isize;
+    let step = step.unwrap_or(1);
+    if step == 0
+    {
+        return None;
+    }
+
+    let (lower, upper) = if step > 0 { (0, len) } else { (-1, len - 1) };
+    let clamp = |bound: isize| {
+        if bound < 0
+        {
+            (bound + len).max(lower)
+        }
+        else
+        {
+            bound.min(upper)
+        }
+    };
+
+    let start = start.map_or(if step > 0 { lower } else { upper }, clamp);
+    let end = end.map_or(if step > 0 { upper } else { lower }, clamp);
+
+    let indices = std::iter::successors(Some(start), move |i| Some(i + step))
+        .take_while(move |&i| {
+            if step > 0
+            {
+                i < end
+            }
+            else
+            {
+                i > end
+            }
+        })
+        .map(|i| i as usize);
+
+    Some(indices)
+}
+
+/// Collects every value stored under `key`, parents before their children
+fn find_key(
+    json_state: &serde_json::Value,
+    key: &str,
+    results: &mut Vec<serde_json::Value>,
+)
+{
+    match json_state
+    {
+        serde_json::Value::Object(object) =>
+        {
+            if let Some(value) = object.get(key)
+            {
+                results.push(value.clone());
+            }
+            for value in object.values()
+            {
+                find_key(value, key, results);
+            }
+        }
+        serde_json::Value::Array(array) =>
+        {
+            for value in array
+            {
+                find_key(value, key, results);
+            }
+        }
+        _ => (),
+    }
+}
+
+fn process_queries(
+    env: &Env,
+    json: serde_json::Value,
+    queries: &Pipeline,
+    output: &Output,
+) -> Result<(), PqError>
+{
+    let mut stdout = std::io::stdout().lock();
+    if output.with_path
+    {
+        for (json_state, path) in
+            path::locate(env, json, Some(vec![]), &queries.steps)?
+        {
+            let path =
+                path.as_ref().map_or(serde_json::Value::Null, path::to_json);
+            let pair = serde_json::Value::Array(vec![path, json_state]);
+            write_value(&mut stdout, pair, output)?;
+        }
+    }
+    else
+    {
+        for json_state in evaluate(env, json, &queries.steps)?
+        {
+            write_value(&mut stdout, json_state, output)?;
+        }
+    }
+    stdout.flush()?;
+
+    Ok(())
+}
+
+fn write_value(
+    out: &mut impl Write,
+    mut value: serde_json::Value,
+    output: &Output,
+) -> Result<(), PqError>
+{
+    if output.sort_keys
+    {
+        sort_keys(&mut value);
+    }
+
+    if !matches!(output.format, Format::Json | Format::Ndjson)
+    {
+        return format::write(out, &value, output);
+    }
+
+    match (&value, output.indent)
+    {
+        (serde_json::Value::String(string), _) if output.raw =>
+        {
+            out.write_all(string.as_bytes())?
+        }
+        (_, None) => serialize(out, &value, CompactFormatter, output.color)?,
+        (_, Some(indent)) =>
+        {
+            let indent = " ".repeat(indent);
+            let formatter = PrettyFormatter::with_indent(indent.as_bytes());
+            serialize(out, &value, formatter, output.color)?
+        }
+    }
+
+    if !output.join
+    {
+        out.write_all(b"\n")?;
+    }
+
+    Ok(())
+}
+
+fn serialize(
+    out: &mut impl Write,
+    value: &serde_json::Value,
+    formatter: impl Formatter,
+    color: bool,
+) -> Result<(), PqError>
+{
+    if color
+    {
+        let formatter = Highlighter { inner: formatter, in_key: false };
+        value.serialize(&mut serde_json::Serializer::with_formatter(
+            out, formatter,
+        ))?;
+    }
+    else
+    {
+        value.serialize(&mut serde_json::Serializer::with_formatter(
+            out, formatter,
+        ))?;
+    }
+
+    Ok(())
+}
+
+/// Wraps another formatter and colors each token it writes
+struct Highlighter<F>
+{
+    inner: F,
+
+    /// Keys are strings too, but they get their own color
+    in_key: bool,
+}
+
+impl<F: Formatter> Highlighter<F>
+{
+    fn paint<W: ?Sized + Write>(
+        writer: &mut W,
+        color: &str,
+        write: impl FnOnce(&mut W) -> std::io::Result<()>,
+    ) -> std::io::Result<()>
+    {
+        writer.write_all(color.as_bytes())?;
+        write(writer)?;
+        writer.write_all(RESET.as_bytes())
+    }
+}
+
+impl<F: Formatter> Formatter for Highlighter<F>
+{
+    fn write_null<W: ?Sized + Write>(
+        &mut self,
+        writer: &mut W,
+    ) -> std::io::Result<()>
+    {
+        Self::paint(writer, RED, |w| self.inner.write_null(w))
+    }
+
+    fn write_bool<W: ?Sized + Write>(
+        &mut self,
+        writer: &mut W,
+        value: bool,
+    ) -> std::io::Result<()>
+    {
+        Self::paint(writer, RED, |w| self.inner.write_bool(w, value))
+    }
+
+    fn write_i64<W: ?Sized + Write>(
+        &mut self,
+        writer: &mut W,
+        value: i64,
+    ) -> std::io::Result<()>
+    {
+        Self::paint(writer, YELLOW, |w| self.inner.write_i64(w, value))
+    }
+
+    fn write_u64<W: ?Sized + Write>(
+        &mut self,
+        writer: &mut W,
+        value: u64,
+    ) -> std::io::Result<()>
+    {
+        Self::paint(writer, YELLOW, |w| self.inner.write_u64(w, value))
+    }
+
+    fn write_f64<W: ?Sized + Write>(
+        &mut self,
+        writer: &mut W,
+        value: f64,
+    ) -> std::io::Result<()>
+    {
+        Self::paint(writer, YELLOW, |w| self.inner.write_f64(w, value))
+    }
+
+    fn begin_string<W: ?Sized + Write>(
+        &mut self,
+        writer: &mut W,
+    ) -> std::io::Result<()>
+    {
+        let color = if self.in_key { BLUE } else { GREEN };
+        writer.write_all(color.as_bytes())?;
+        self.inner.begin_string(writer)
+    }
+
+    fn end_string<W: ?Sized + Write>(
+        &mut self,
+        writer: &mut W,
+    ) -> std::io::Result<()>
+    {
+        self.inner.end_string(writer)?;
+        writer.write_all(RESET.as_bytes())
+    }
+
+    fn begin_array<W: ?Sized + Write>(
+        &mut self,
+        writer: &mut W,
+    ) -> std::io::Result<()>
+    {
+        self.inner.begin_array(writer)
+    }
+
+    fn end_array<W: ?Sized + Write>(
+        &mut self,
+        writer: &mut W,
+    ) -> std::io::Result<()>
+    {
+        self.inner.end_array(writer)
+    }
+
+    fn begin_array_value<W: ?Sized + Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> std::io::Result<()>
+    {
+        self.inner.begin_array_value(writer, first)
+    }
+
+    fn end_array_value<W: ?Sized + Write>(
+        &mut self,
+        writer: &mut W,
+    ) -> std::io::Result<()>
+    {
+        self.inner.end_array_value(writer)
+    }
+
+    fn begin_object<W: ?Sized + Write>(
+        &mut self,
+        writer: &mut W,
+    ) -> std::io::Result<()>
+    {
+        self.inner.begin_object(writer)
+    }
+
+    fn end_object<W: ?Sized + Write>(
+        &mut self,
+        writer: &mut W,
+    ) -> std::io::Result<()>
+    {
+        self.inner.end_object(writer)
+    }
+
+    fn begin_object_key<W: ?Sized + Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> std::io::Result<()>
+    {
+        self.in_key = true;
+        self.inner.begin_object_key(writer, first)
+    }
+
+    fn end_object_key<W: ?Sized + Write>(
+        &mut self,
+        writer: &mut W,
+    ) -> std::io::Result<()>
+    {
+        self.in_key = false;
+        self.inner.end_object_key(writer)
+    }
+
+    fn begin_object_value<W: ?Sized + Write>(
+        &mut self,
+        writer: &mut W,
+    ) -> std::io::Result<()>
+    {
+        self.inner.begin_object_value(writer)
+    }
+
+    fn end_object_value<W: ?Sized + Write>(
+        &mut self,
+        writer: &mut W,
+    ) -> std::io::Result<()>
+    {
+        self.inner.end_object_value(writer)
+    }
+}
+
+fn sort_keys(value: &mut serde_json::Value)
+{
+    match value
+    {
+        serde_json::Value::Object(object) =>
+        {
+            object.sort_keys();
+            object.values_mut().for_each(sort_keys);
+        }
+        serde_json::Value::Array(array) => array.iter_mut().for_each(sort_keys),
+        _ => (),
+    }
+}
+
+/// Runs the remaining queries once for every value the first query produces,
+/// so a fanout anywhere in the chain multiplies the results that come out.
+fn evaluate(
+    env: &Env,
+    json_state: serde_json::Value,
+    queries: &[Spanned<Query>],
+) -> Result<Vec<serde_json::Value>, PqError>
+{
+    let Some(query) = queries.first()
+    else
+    {
+        return Ok(vec![json_state]);
+    };
+
+    let depth = env.depth.get();
+    if depth >= MAX_DEPTH
+    {
+        return Err(PqError::Type {
+            message: "query nests too deep".to_string(),
+            span: query.span.clone(),
+        });
+    }
+
+    env.depth.set(depth + 1);
+    let results = evaluate_steps(env, json_state, queries);
+    env.depth.set(depth);
+    results
+}
+
+/// `evaluate` without counting towards `MAX_DEPTH`, for the steps after the
+/// first of the same pipeline
+fn evaluate_steps(
+    env: &Env,
+    json_state: serde_json::Value,
+    queries: &[Spanned<Query>],
+) -> Result<Vec<serde_json::Value>, PqError>
+{
+    let Some((query, rest)) = queries.split_first()
+    else
+    {
+        return Ok(vec![json_state]);
+    };
+
+    trace!("    {json_state}");
+
+    let mut results = vec![];
+    let json_states = process_query(env, json_state, query)
+        .map_err(|err| err.with_span(&query.span))?;
+    for json_state in json_states
+    {
+        results.extend(evaluate_steps(env, json_state, rest)?);
+    }
+
+    Ok(results)
+}
+
+/// With `--strict`, selecting a key or index that is not there fails instead
+/// of giving back `null`
+fn check_strict(
+    env: &Env,
+    json_state: &serde_json::Value,
+    query: &Spanned<Query>,
+) -> Result<(), PqError>
+{
+    if !env.strict
+    {
+        return Ok(());
+    }
+
+    let message = match (&query.inner, json_state)
+    {
+        (Query::SelectKey { key }, serde_json::Value::Object(object))
+            if !object.contains_key(key) =>
+        {
+            format!("key `{key}` is missing")
+        }
+        (Query::SelectKey { key }, serde_json::Value::Null) =>
+        {
+            format!("cannot select key `{key}` from null")
+        }
+        (Query::Index { index }, serde_json::Value::Array(array)) =>
+        {
+            let len = array.len() as isize;
+            let position = if *index < 0 { len + index } else { *index };
+            if (0 .. len).contains(&position)
+            {
+                return Ok(());
+            }
+            format!("index {index} is out of range for {len} items")
+        }
+        (Query::Index { .. }, serde_json::Value::Null) =>
+        {
+            "cannot index null".to_string()
+        }
+        _ => return Ok(()),
+    };
+
+    Err(PqError::Type { message, span: query.span.clone() })
+}
+
+/// The field `key` copied into an object being built from `json_state`,
+/// which fails where selecting `key` as a step would
+fn select_field(
+    env: &Env,
+    json_state: &serde_json::Value,
+    key: &str,
+    span: &Span,
+) -> Result<serde_json::Value, PqError>
+{
+    let step =
+        Spanned::new(Query::SelectKey { key: key.to_string() }, span.clone());
+    check_strict(env, json_state, &step)?;
+
+    match json_state
+    {
+        serde_json::Value::Object(_) | serde_json::Value::Null =>
+        {
+            Ok(json_state[key].clone())
+        }
+        _ => Err(PqError::Type {
+            message: format!(
+                "cannot select key `{key}` from {}",
+                type_name(json_state)
+            ),
+            span: span.clone(),
+        }),
+    }
+}
+
+/// Treats a step that failed on the value it was given as one that gave back
+/// nothing, for `step?` and the left side of `//`
+fn recover(
+    result: Result<Vec<serde_json::Value>, PqError>,
+) -> Result<Vec<serde_json::Value>, PqError>
+{
+    match result
+    {
+        Err(PqError::Type { .. } | PqError::Python { .. }) => Ok(vec![]),
+        result => result,
+    }
+}
+
+fn process_query(
+    env: &Env,
+    json_state: serde_json::Value,
+    query: &Spanned<Query>,
+) -> Result<Vec<serde_json::Value>, PqError>
+{
+    let mut json_state = json_state;
+    let type_error =
+        |message: String| PqError::Type { message, span: query.span.clone() };
+    check_strict(env, &json_state, query)?;
+
+    match &query.inner
+    {
+        Query::SelectKey { key } =>
+        {
+            json_state = match json_state
+            {
+                serde_json::Value::Object(mut object) =>
+                {
+                    object.remove(key).unwrap_or_default()
+                }
+                serde_json::Value::Null => serde_json::Value::Null,
+                _ =>
+                {
+                    return Err(type_error(format!(
+                        "cannot select key `{key}` from {}",
+                        type_name(&json_state)
+                    )));
+                }
+            };
+        }
+        Query::Index { index } =>
+        {
+            json_state = match json_state
+            {
+                serde_json::Value::Array(mut array) =>
+                {
+                    let key = if *index < 0
+                    {
+                        array.len() as isize + index
+                    }
+                    else
+                    {
+                        *index
+                    };
+                    match usize::try_from(key)
+                    {
+                        Ok(key) if key < array.len() => array.swap_remove(key),
+                        _ => serde_json::Value::Null,
+                    }
+                }
+                serde_json::Value::Null => serde_json::Value::Null,
+                _ =>
+                {
+                    return Err(type_error(format!(
+                        "cannot index {} with a number",
+                        type_name(&json_state)
+                    )));
+                }
+            };
+        }
+        Query::Slice { start, end, step } =>
+        {
+            let indices = |len: usize| {
+                slice_indices(len, *start, *end, *step).ok_or_else(|| {
+                    type_error("slice step cannot be zero".to_string())
+                })
+            };
+
+            json_state = match json_state
+            {
+                serde_json::Value::Array(array) =>
+                {
+                    let indices = indices(array.len())?;
+                    let array = indices.map(|i| array[i].clone()).collect();
+                    serde_json::Value::Array(array)
+                }
+                serde_json::Value::String(string) =>
+                {
+                    let chars: Vec<char> = string.chars().collect();
+                    let indices = indices(chars.len())?;
+                    serde_json::Value::String(
+                        indices.map(|i| chars[i]).collect(),
+                    )
+                }
+                serde_json::Value::Null => serde_json::Value::Null,
+                _ =>
+                {
+                    return Err(type_error(format!(
+                        "cannot slice {}",
+                        type_name(&json_state)
+                    )));
+                }
+            };
+        }
+        Query::BuildObject { entries } =>
+        {
+            let mut new_json_state = serde_json::json!({});
+            let locals = ObjectLocals::new(&json_state);
+            for ObjectEntry { key, value } in entries
+            {
+                // A bare key names the field its value expression sees as
+                // `_`, a Python key sees the whole object instead. The key
+                // can be new, so a missing field is `None` even with
+                // `--strict`.
+                let (result_key, field) = match &key.inner
+                {
+                    ObjectKey::Name(name) => (name.clone(), &json_state[name]),
+                    ObjectKey::Python(source) =>
+                    {
+                        let result_key = Python::with_gil(|py| {
+                            locals
+                                .eval(py, env, &json_state, source)?
+                                .extract::<String>()
+                                .map_err(PqError::from)
+                        })
+                        .map_err(|err| err.with_span(&key.span))?;
+                        (result_key, &json_state)
+                    }
+                };
+
+                new_json_state[result_key] = match &value.inner
+                {
+                    ObjectValue::Field(field) =>
+                    {
+                        select_field(env, &json_state, field, &value.span)?
+                    }
+                    ObjectValue::Variable(name) =>
+                    {
+                        env.variable(name, &value.span)?
+                    }
+                    ObjectValue::Python(source) => Python::with_gil(|py| {
+                        let result = locals.eval(py, env, field, source)?;
+                        from_python(&result)
+                    })
+                    .map_err(|err| err.with_span(&value.span))?,
+                };
+            }
+            json_state = new_json_state;
+        }
+        Query::Expression { source } =>
+        {
+            json_state = Python::with_gil(|py| {
+                let result = eval_python(py, env, &json_state, source)?;
+                from_python(&result)
+            })?;
+        }
+        Query::Fanout =>
+        {
+            return match json_state
+            {
+                serde_json::Value::Array(array) => Ok(array),
+                serde_json::Value::Object(object) =>
+                {
+                    Ok(object.into_iter().map(|(_, v)| v).collect())
+                }
+                _ => Err(type_error(format!(
+                    "cannot fan out over {}",
+                    type_name(&json_state)
+                ))),
+            };
+        }
+        Query::Recurse { key } =>
+        {
+            let mut results = vec![];
+            find_key(&json_state, key, &mut results);
+            return Ok(results);
+        }
+        Query::Join { branches, collect } =>
+        {
+            let mut results = vec![];
+            for branch in branches
+            {
+                results.extend(evaluate(
+                    env,
+                    json_state.clone(),
+                    &branch.steps,
+                )?);
+            }
+
+            if *collect
+            {
+                return Ok(vec![serde_json::Value::Array(results)]);
+            }
+
+            return Ok(results);
+        }
+        Query::Variable { name } =>
+        {
+            json_state = env.variable(name, &query.span)?;
+        }
+        Query::Assign { path, update, value } =>
+        {
+            return path::assign(
+                env,
+                json_state,
+                path,
+                *update,
+                value,
+                &query.span,
+            );
+        }
+        Query::Optional { step } =>
+        {
+            return recover(process_query(env, json_state, step));
+        }
+        Query::Alternative { first, fallback } =>
+        {
+            let results =
+                recover(evaluate(env, json_state.clone(), &first.steps))?;
+            let truthy: Vec<_> = results
+                .into_iter()
+                .filter(|value| {
+                    !matches!(
+                        value,
+                        serde_json::Value::Null
+                            | serde_json::Value::Bool(false)
+                    )
+                })
+                .collect();
+            if truthy.is_empty()
+            {
+                return evaluate(env, json_state, &fallback.steps);
+            }
+
+            return Ok(truthy);
+        }
+        Query::Call { name, args } =>
+        {
+            let Some(function) = env.functions.get(name)
+            else
+            {
+                return Err(type_error(format!("`{name}` is not defined")));
+            };
+
+            // Arguments are evaluated where the call is, before the body
+            // can see them
+            let mut scope = serde_json::Map::new();
+            for (param, arg) in function.params.iter().zip(args)
+            {
+                let results = evaluate(env, json_state.clone(), &arg.steps)?;
+                let Some(value) = results.into_iter().next()
+                else
+                {
+                    return Ok(vec![]);
+                };
+                scope.insert(param.clone(), value);
+            }
+
+            // Named here, since a function calling itself is the usual way
+            // to nest this deep
+            if env.depth.get() >= MAX_DEPTH
+            {
+                return Err(type_error(format!(
+                    "recursion too deep in `{name}`"
+                )));
+            }
+
+            env.scopes.borrow_mut().push(scope);
+            let results = evaluate(env, json_state, &function.body.steps);
+            env.scopes.borrow_mut().pop();
+
+            return results.map_err(|err| match (&function.file, err)
+            {
+                // The body's spans point into a library, not the query
+                (Some(file), PqError::Type { message, .. }) =>
+                {
+                    // Recursion names the function once, not once a call
+                    let context = format!(", in `{name}` from {file}");
+                    if message.ends_with(&context)
+                    {
+                        type_error(message)
+                    }
+                    else
+                    {
+                        type_error(format!("{message}{context}"))
+                    }
+                }
+                (Some(_), PqError::Python { err, .. }) =>
+                {
+                    PqError::Python { err, span: Some(query.span.clone()) }
+                }
+                (_, err) => err,
+            });
+        }
+        Query::Builtin { builtin } =>
+        {
+            json_state = builtins::call(env, builtin, json_state, &query.span)?;
+        }
+        Query::Select { source } =>
+        {
+            let keep = Python::with_gil::<_, Result<bool, PqError>>(|py| {
+                let result = eval_python(py, env, &json_state, source)?;
+                Ok(result.is_truthy()?)
+            })?;
+
+            if !keep
+            {
+                return Ok(vec![]);
+            }
+        }
+    }
+
+    Ok(vec![json_state])
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn slice(
+        len: usize,
+        start: Option<isize>,
+        end: Option<isize>,
+        step: Option<isize>,
+    ) -> Vec<usize>
+    {
+        slice_indices(len, start, end, step).unwrap().collect()
+    }
+
+    #[test]
+    fn slice_indices_like_python()
+    {
+        assert_eq!(slice(5, None, None, None), [0, 1, 2, 3, 4]);
+        assert_eq!(slice(5, Some(1), Some(3), None), [1, 2]);
+        assert_eq!(slice(5, Some(-2), None, None), [3, 4]);
+        assert_eq!(slice(5, None, Some(-1), None), [0, 1, 2, 3]);
+        assert!(slice(5, Some(3), Some(1), None).is_empty());
+    }
+
+    #[test]
+    fn slice_indices_clamp_out_of_range_bounds()
+    {
+        assert_eq!(slice(5, Some(-10), Some(10), None), [0, 1, 2, 3, 4]);
+        assert_eq!(slice(5, Some(10), None, Some(-2)), [4, 2, 0]);
+        assert_eq!(slice(5, None, Some(-10), Some(-1)), [4, 3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn slice_indices_with_negative_steps()
+    {
+        assert_eq!(slice(5, None, None, Some(-1)), [4, 3, 2, 1, 0]);
+        assert_eq!(slice(5, Some(3), Some(0), Some(-1)), [3, 2, 1]);
+        assert_eq!(slice(5, Some(-1), Some(-4), Some(-2)), [4, 2]);
+        assert_eq!(slice(5, Some(2), None, Some(-10)), [2]);
+        assert!(slice(0, None, None, Some(-1)).is_empty());
+    }
+
+    #[test]
+    fn slice_indices_refuse_a_zero_step()
+    {
+        assert!(slice_indices(5, None, None, Some(0)).is_none());
+    }
+}
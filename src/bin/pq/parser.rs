@@ -0,0 +1,682 @@
+//! Recursive descent over the tokens of a query
+
+use rustpython_parser::Tok;
+
+use std::collections::HashMap;
+
+use crate::ast::{
+    Builtin, Function, ObjectEntry, ObjectKey, ObjectValue, Pipeline, Query,
+    Spanned,
+};
+use crate::lexer::{Lexer, Token};
+use crate::{PqError, QueryError, QueryErrorKind, Span};
+
+/// The functions a query starts by defining, and the query itself
+pub fn parse_queries(input: &str)
+    -> Result<(Vec<Function>, Pipeline), PqError>
+{
+    trace!("{input}");
+
+    let mut lexer = Lexer::new(input);
+    let functions = expect_definitions(&mut lexer, None)?;
+    let pipeline = expect_joined(&mut lexer)?;
+    let (token, span) = lexer.next()?;
+    if token != Token::End
+    {
+        return Err(lexer.unexpected(span.start, &["`,`", "end of query"]));
+    }
+
+    Ok((functions, pipeline))
+}
+
+/// A `.pq` file, which holds nothing but definitions
+pub fn parse_library(input: &str, file: &str)
+    -> Result<Vec<Function>, PqError>
+{
+    let mut lexer = Lexer::new(input);
+    let functions = expect_definitions(&mut lexer, Some(file))?;
+    let (token, span) = lexer.next()?;
+    if token != Token::End
+    {
+        return Err(lexer.unexpected(span.start, &["`def`", "end of file"]));
+    }
+
+    Ok(functions)
+}
+
+/// Makes sure every function called in `pipeline` is defined and given as
+/// many arguments as it takes, which can only be known once every library is
+/// loaded
+pub fn check_calls(
+    pipeline: &Pipeline,
+    functions: &HashMap<String, Function>,
+) -> Result<(), PqError>
+{
+    pipeline.steps.iter().try_for_each(|step| check_step(step, functions))
+}
+
+fn check_step(
+    step: &Spanned<Query>,
+    functions: &HashMap<String, Function>,
+) -> Result<(), PqError>
+{
+    if let Query::Call { name, args } = &step.inner
+    {
+        let kind = match functions.get(name)
+        {
+            None => Some(QueryErrorKind::UnknownFunction(name.clone())),
+            Some(function) if function.params.len() != args.len() =>
+            {
+                Some(QueryErrorKind::WrongArguments {
+                    name: name.clone(),
+                    expected: function.params.len(),
+                })
+            }
+            Some(_) => None,
+        };
+        if let Some(kind) = kind
+        {
+            let offset = step.span.start;
+            return Err(PqError::Query(QueryError {
+                kind,
+                offset,
+                expected: vec![],
+            }));
+        }
+    }
+
+    if let Query::Optional { step } = &step.inner
+    {
+        check_step(step, functions)?;
+    }
+
+    step.inner
+        .pipelines()
+        .into_iter()
+        .try_for_each(|pipeline| check_calls(pipeline, functions))
+}
+
+/// `def` only starts a definition when a name and `(` follow, so `def` alone
+/// can still be a key
+fn accept_definition(lexer: &Lexer) -> Result<bool, PqError>
+{
+    let mut ahead = lexer.clone();
+    if ahead.next()?.0 != Token::Ident("def".to_string()) || ahead.adjacent()
+    {
+        return Ok(false);
+    }
+
+    Ok(matches!(ahead.next()?.0, Token::Ident(..))
+        && ahead.adjacent()
+        && ahead.peek()?.0 == Token::LParen)
+}
+
+fn expect_definitions(
+    lexer: &mut Lexer,
+    file: Option<&str>,
+) -> Result<Vec<Function>, PqError>
+{
+    let mut functions = vec![];
+    while accept_definition(lexer)?
+    {
+        lexer.next()?; // Skip `def`
+        let (Token::Ident(name), span) = lexer.next()?
+        else
+        {
+            unreachable!("`accept_definition` only accepts a name");
+        };
+        if Builtin::NAMES.contains(&name.as_str())
+        {
+            let kind = QueryErrorKind::RedefinedBuiltin(name);
+            return Err(lexer.error(span.start, kind, &[]));
+        }
+        lexer.next()?; // Skip `(`
+
+        let mut params = vec![];
+        loop
+        {
+            match lexer.next()?
+            {
+                (Token::RParen, _) if params.is_empty() => break,
+                (Token::Variable(param), _) => params.push(param),
+                (_, span) =>
+                {
+                    return Err(lexer.unexpected(span.start, &["`$`", "`)`"]));
+                }
+            }
+            match lexer.next()?
+            {
+                (Token::Comma, _) => (),
+                (Token::RParen, _) => break,
+                (_, span) =>
+                {
+                    return Err(lexer.unexpected(span.start, &["`,`", "`)`"]));
+                }
+            }
+        }
+
+        expect_token(lexer, Token::Colon, &["`:`"])?;
+        let body = expect_joined(lexer)?;
+        expect_token(lexer, Token::Semicolon, &["`,`", "`;`"])?;
+
+        let file = file.map(str::to_string);
+        functions.push(Function { name, params, body, file });
+    }
+
+    Ok(functions)
+}
+
+/// Pipelines separated by `,`
+fn expect_branches(lexer: &mut Lexer) -> Result<Vec<Pipeline>, PqError>
+{
+    let mut branches = vec![expect_pipeline(lexer)?];
+    while lexer.peek()?.0 == Token::Comma
+    {
+        lexer.next()?;
+        branches.push(expect_pipeline(lexer)?);
+    }
+
+    Ok(branches)
+}
+
+/// Parses chained queries up to the end of input or an unmatched `,`, `]` or
+/// `)`
+fn expect_pipeline(lexer: &mut Lexer) -> Result<Pipeline, PqError>
+{
+    let (_, span) = lexer.peek()?;
+    lexer.enter(span.start)?;
+    let steps = expect_steps(lexer)?;
+    lexer.leave();
+
+    Ok(Pipeline { steps })
+}
+
+fn expect_steps(lexer: &mut Lexer) -> Result<Vec<Spanned<Query>>, PqError>
+{
+    let mut steps = vec![];
+
+    loop
+    {
+        let (token, span) = lexer.peek()?;
+        let query = match token
+        {
+            Token::Comma
+            | Token::RBracket
+            | Token::RParen
+            | Token::Semicolon
+            | Token::End => break,
+            Token::Dot =>
+            {
+                lexer.next()?;
+                if !(lexer.adjacent() && lexer.peek()?.0 == Token::Star)
+                {
+                    continue; // A plain `.` only separates steps
+                }
+                let (_, star) = lexer.next()?;
+                Spanned::new(Query::Fanout, span.start .. star.end)
+            }
+            Token::DotDot => expect_recurse(lexer)?,
+            Token::LBrace => expect_build_object(lexer)?,
+            Token::LParen => expect_expression(lexer)?,
+            Token::Question => expect_select(lexer)?,
+            Token::LBracket => expect_bracket(lexer)?,
+            Token::Ident(..) if accept_call(lexer)? => expect_call(lexer)?,
+            Token::Ident(key) | Token::Str(key) =>
+            {
+                lexer.next()?;
+                Spanned::new(Query::SelectKey { key }, span)
+            }
+            Token::Variable(name) =>
+            {
+                lexer.next()?;
+                Spanned::new(Query::Variable { name }, span)
+            }
+            Token::Assign | Token::Update =>
+            {
+                let path = Pipeline { steps: std::mem::take(&mut steps) };
+                expect_assign(lexer, path)?
+            }
+            Token::Alternative =>
+            {
+                let first = Pipeline { steps: std::mem::take(&mut steps) };
+                expect_alternative(lexer, first)?
+            }
+            _ =>
+            {
+                return Err(lexer.unexpected(span.start, &[
+                    "key", "`\"`", "`.`", "`[`", "`{`", "`(`", "`?(`", "`$`",
+                ]));
+            }
+        };
+        steps.push(expect_optional(lexer, query)?);
+    }
+
+    Ok(steps)
+}
+
+fn expect_token(
+    lexer: &mut Lexer,
+    expected: Token,
+    names: &[&'static str],
+) -> Result<Span, PqError>
+{
+    let (token, span) = lexer.next()?;
+    if token != expected
+    {
+        return Err(lexer.unexpected(span.start, names));
+    }
+
+    Ok(span)
+}
+
+/// A name with `(` right after it calls a function instead of selecting a key
+fn accept_call(lexer: &Lexer) -> Result<bool, PqError>
+{
+    let mut ahead = lexer.clone();
+    ahead.next()?;
+    Ok(ahead.adjacent() && ahead.peek()?.0 == Token::LParen)
+}
+
+fn expect_call(lexer: &mut Lexer) -> Result<Spanned<Query>, PqError>
+{
+    let (Token::Ident(name), span) = lexer.next()?
+    else
+    {
+        unreachable!("`accept_call` only accepts a name");
+    };
+    lexer.next()?; // Skip `(`
+
+    let builtin = match name.as_str()
+    {
+        "length" => Builtin::Length,
+        "keys" => Builtin::Keys,
+        "values" => Builtin::Values,
+        "to_entries" => Builtin::ToEntries,
+        "from_entries" => Builtin::FromEntries,
+        "sort_by" => Builtin::SortBy(expect_joined(lexer)?),
+        "group_by" => Builtin::GroupBy(expect_joined(lexer)?),
+        "unique" => Builtin::Unique,
+        "min" => Builtin::Min,
+        "max" => Builtin::Max,
+        "add" => Builtin::Add,
+        "del" => Builtin::Delete(expect_path(lexer)?),
+        "paths" => Builtin::Paths,
+        "leaf_paths" => Builtin::LeafPaths,
+        "getpath" => Builtin::GetPath(expect_pipeline(lexer)?),
+        "setpath" =>
+        {
+            let path = expect_pipeline(lexer)?;
+            expect_token(lexer, Token::Comma, &["`,`"])?;
+            Builtin::SetPath(path, expect_pipeline(lexer)?)
+        }
+        "flatten" => match lexer.peek()?
+        {
+            (Token::Int(depth), _) =>
+            {
+                lexer.next()?;
+                Builtin::Flatten(Some(depth as usize))
+            }
+            _ => Builtin::Flatten(None),
+        },
+        // Defined by the query or a library, which `check_calls` makes
+        // sure of once they are all known
+        _ =>
+        {
+            let mut args = vec![];
+            if lexer.peek()?.0 != Token::RParen
+            {
+                args = expect_branches(lexer)?;
+            }
+            let close = expect_token(lexer, Token::RParen, &["`,`", "`)`"])?;
+
+            let query = Query::Call { name, args };
+            return Ok(Spanned::new(query, span.start .. close.end));
+        }
+    };
+    let close = expect_token(lexer, Token::RParen, &["`)`"])?;
+
+    let query = Query::Builtin { builtin };
+    Ok(Spanned::new(query, span.start .. close.end))
+}
+
+/// Pipelines separated by `,` as one that gives the results of each in turn,
+/// so the key of `sort_by(a, b)` compares by `a`, then by `b`
+fn expect_joined(lexer: &mut Lexer) -> Result<Pipeline, PqError>
+{
+    let (_, span) = lexer.peek()?;
+    let mut branches = expect_branches(lexer)?;
+    if branches.len() == 1
+    {
+        return Ok(branches.remove(0));
+    }
+
+    let (_, close) = lexer.peek()?;
+    let query = Query::Join { branches, collect: false };
+    Ok(Pipeline { steps: vec![Spanned::new(query, span.start .. close.start)] })
+}
+
+/// `step?` gives back nothing where `step` would fail, while `step?(...)`
+/// still selects
+fn expect_optional(
+    lexer: &mut Lexer,
+    step: Spanned<Query>,
+) -> Result<Spanned<Query>, PqError>
+{
+    if !lexer.adjacent() || lexer.peek()?.0 != Token::Question
+    {
+        return Ok(step);
+    }
+
+    let mut ahead = lexer.clone();
+    let (_, question) = ahead.next()?;
+    if ahead.adjacent() && ahead.peek()?.0 == Token::LParen
+    {
+        return Ok(step);
+    }
+    *lexer = ahead;
+
+    let span = step.span.start .. question.end;
+    Ok(Spanned::new(Query::Optional { step: Box::new(step) }, span))
+}
+
+/// `first // fallback`, where the fallback takes up the rest of the pipeline
+fn expect_alternative(
+    lexer: &mut Lexer,
+    first: Pipeline,
+) -> Result<Spanned<Query>, PqError>
+{
+    let (_, op) = lexer.next()?;
+    if first.steps.is_empty()
+    {
+        return Err(lexer.unexpected(op.start, &["a value"]));
+    }
+    let fallback = expect_pipeline(lexer)?;
+    if fallback.steps.is_empty()
+    {
+        let (_, span) = lexer.peek()?;
+        return Err(lexer.unexpected(span.start, &["a value"]));
+    }
+
+    let start = first.steps[0].span.start;
+    let end = fallback.steps.last().map_or(op.end, |step| step.span.end);
+    let query = Query::Alternative { first, fallback };
+    Ok(Spanned::new(query, start .. end))
+}
+
+/// `path = value` or `path |= value`, where the value takes up the rest of
+/// the pipeline
+fn expect_assign(
+    lexer: &mut Lexer,
+    path: Pipeline,
+) -> Result<Spanned<Query>, PqError>
+{
+    check_path(lexer, &path)?;
+    let (token, op) = lexer.next()?;
+    let value = expect_pipeline(lexer)?;
+    if value.steps.is_empty()
+    {
+        let (_, span) = lexer.peek()?;
+        return Err(lexer.unexpected(span.start, &["a value"]));
+    }
+
+    let start = path.steps.first().map_or(op.start, |step| step.span.start);
+    let end = value.steps.last().map_or(op.end, |step| step.span.end);
+    let update = token == Token::Update;
+    let query = Query::Assign { path, update, value };
+    Ok(Spanned::new(query, start .. end))
+}
+
+fn expect_path(lexer: &mut Lexer) -> Result<Pipeline, PqError>
+{
+    let path = expect_pipeline(lexer)?;
+    check_path(lexer, &path)?;
+    Ok(path)
+}
+
+/// Only keys, indices, `[]`, `..key` and `?(...)` point somewhere in the
+/// document
+fn check_path(lexer: &Lexer, path: &Pipeline) -> Result<(), PqError>
+{
+    match path.steps.iter().find(|step| !step.inner.is_path())
+    {
+        Some(step) =>
+        {
+            Err(lexer.error(step.span.start, QueryErrorKind::NotAPath, &[]))
+        }
+        None => Ok(()),
+    }
+}
+
+/// `..key` finds `key` at any depth below the current value
+fn expect_recurse(lexer: &mut Lexer) -> Result<Spanned<Query>, PqError>
+{
+    let (_, dots) = lexer.next()?;
+    match lexer.next()?
+    {
+        (Token::Ident(key) | Token::Str(key), span)
+            if dots.end == span.start =>
+        {
+            Ok(Spanned::new(Query::Recurse { key }, dots.start .. span.end))
+        }
+        (_, span) => Err(lexer.unexpected(span.start, &["key", "`\"`"])),
+    }
+}
+
+/// Everything that starts with `[`: a fanout `[]`, an index `[-1]`, a slice
+/// `[1::2]`, a quoted key `["some key"]` or a collecting join `[a, b]`
+fn expect_bracket(lexer: &mut Lexer) -> Result<Spanned<Query>, PqError>
+{
+    let (_, open) = lexer.next()?;
+    let (token, span) = lexer.peek()?;
+    match token
+    {
+        Token::RBracket =>
+        {
+            lexer.next()?;
+            Ok(Spanned::new(Query::Fanout, open.start .. span.end))
+        }
+        Token::Minus | Token::Int(..) | Token::Colon =>
+        {
+            expect_index_or_slice(lexer, open)
+        }
+        Token::Str(key) =>
+        {
+            let mut ahead = lexer.clone();
+            ahead.next()?;
+            match ahead.next()?
+            {
+                (Token::RBracket, close) =>
+                {
+                    *lexer = ahead;
+                    let query = Query::SelectKey { key };
+                    Ok(Spanned::new(query, open.start .. close.end))
+                }
+                _ => expect_join(lexer, open),
+            }
+        }
+        _ => expect_join(lexer, open),
+    }
+}
+
+fn expect_index_or_slice(
+    lexer: &mut Lexer,
+    open: Span,
+) -> Result<Spanned<Query>, PqError>
+{
+    let start = expect_bound(lexer)?;
+    if let (Some(index), Token::RBracket) = (start, lexer.peek()?.0)
+    {
+        let (_, close) = lexer.next()?;
+        let query = Query::Index { index };
+        return Ok(Spanned::new(query, open.start .. close.end));
+    }
+
+    expect_token(lexer, Token::Colon, &["`:`", "`]`"])?;
+    let end = expect_bound(lexer)?;
+    let mut step = None;
+    if lexer.peek()?.0 == Token::Colon
+    {
+        lexer.next()?;
+        step = expect_bound(lexer)?;
+    }
+    let close = expect_token(lexer, Token::RBracket, &["`:`", "`]`"])?;
+
+    let query = Query::Slice { start, end, step };
+    Ok(Spanned::new(query, open.start .. close.end))
+}
+
+/// An optional, optionally negative, integer in an index or slice
+fn expect_bound(lexer: &mut Lexer) -> Result<Option<isize>, PqError>
+{
+    let negative = lexer.peek()?.0 == Token::Minus;
+    if negative
+    {
+        lexer.next()?;
+    }
+
+    match lexer.peek()?
+    {
+        (Token::Int(int), _) =>
+        {
+            lexer.next()?;
+            Ok(Some(if negative { -int } else { int }))
+        }
+        (_, span) if negative => Err(lexer.unexpected(span.start, &["index"])),
+        _ => Ok(None),
+    }
+}
+
+fn expect_join(lexer: &mut Lexer, open: Span)
+    -> Result<Spanned<Query>, PqError>
+{
+    let branches = expect_branches(lexer)?;
+    let close = expect_token(lexer, Token::RBracket, &["`,`", "`]`"])?;
+
+    let query = Query::Join { branches, collect: true };
+    Ok(Spanned::new(query, open.start .. close.end))
+}
+
+fn expect_expression(lexer: &mut Lexer) -> Result<Spanned<Query>, PqError>
+{
+    let (source, span) = lexer.python_parenthesized()?;
+    Ok(Spanned::new(Query::Expression { source }, span))
+}
+
+/// A Python predicate wrapped as `?(expr)`
+fn expect_select(lexer: &mut Lexer) -> Result<Spanned<Query>, PqError>
+{
+    let (_, question) = lexer.next()?;
+    let (token, span) = lexer.peek()?;
+    if !lexer.adjacent() || token != Token::LParen
+    {
+        return Err(lexer.unexpected(span.start, &["`(`"]));
+    }
+
+    let (source, span) = lexer.python_parenthesized()?;
+    Ok(Spanned::new(Query::Select { source }, question.start .. span.end))
+}
+
+fn expect_build_object(lexer: &mut Lexer) -> Result<Spanned<Query>, PqError>
+{
+    let (_, open) = lexer.next()?;
+    let mut entries = vec![];
+
+    let close = loop
+    {
+        // Allows `{}` and a trailing `,`
+        if !lexer.at(&['"', '\''])
+        {
+            if let (Token::RBrace, close) = lexer.peek()?
+            {
+                lexer.next()?;
+                break close;
+            }
+        }
+
+        let part = expect_object_part(lexer)?;
+        let entry = match (lexer.peek()?, &part.inner)
+        {
+            ((Token::Colon, _), ObjectValue::Field(key)) =>
+            {
+                lexer.next()?;
+                let key = Spanned::new(ObjectKey::Name(key.clone()), part.span);
+                ObjectEntry { key, value: expect_object_part(lexer)? }
+            }
+            ((Token::Colon, _), ObjectValue::Python(source)) =>
+            {
+                lexer.next()?;
+                let key = ObjectKey::Python(source.clone());
+                let key = Spanned::new(key, part.span);
+                ObjectEntry { key, value: expect_object_part(lexer)? }
+            }
+            ((Token::Colon, _), ObjectValue::Variable(..)) =>
+            {
+                return Err(
+                    lexer.unexpected(part.span.start, &["key", "`\"`", "`(`"])
+                );
+            }
+            (_, ObjectValue::Field(name) | ObjectValue::Variable(name)) =>
+            {
+                let key = ObjectKey::Name(name.clone());
+                let key = Spanned::new(key, part.span.clone());
+                ObjectEntry { key, value: part }
+            }
+            ((_, span), ObjectValue::Python(..)) =>
+            {
+                return Err(lexer.unexpected(span.start, &["`:`"]));
+            }
+        };
+        entries.push(entry);
+
+        match lexer.next()?
+        {
+            (Token::Comma, _) => (),
+            (Token::RBrace, close) => break close,
+            (_, span) =>
+            {
+                return Err(lexer.unexpected(span.start, &["`,`", "`}`"]));
+            }
+        }
+    };
+
+    trace!("Entries :: {entries:?}");
+
+    let query = Query::BuildObject { entries };
+    Ok(Spanned::new(query, open.start .. close.end))
+}
+
+/// A key or value in object construction, where a string starts a Python
+/// expression that runs up to the next `:`, `,` or `}`
+fn expect_object_part(
+    lexer: &mut Lexer,
+) -> Result<Spanned<ObjectValue>, PqError>
+{
+    if lexer.at(&['"', '\''])
+    {
+        let stops = [Tok::Colon, Tok::Comma, Tok::Rbrace];
+        let (source, span) = lexer.python_until(&stops)?;
+        let value = ObjectValue::Python(format!("({source})"));
+        return Ok(Spanned::new(value, span));
+    }
+
+    let (token, span) = lexer.peek()?;
+    match token
+    {
+        Token::LParen =>
+        {
+            let (source, span) = lexer.python_parenthesized()?;
+            Ok(Spanned::new(ObjectValue::Python(source), span))
+        }
+        Token::Ident(key) =>
+        {
+            lexer.next()?;
+            Ok(Spanned::new(ObjectValue::Field(key), span))
+        }
+        Token::Variable(name) =>
+        {
+            lexer.next()?;
+            Ok(Spanned::new(ObjectValue::Variable(name), span))
+        }
+        _ => Err(lexer.unexpected(span.start, &["key", "`\"`", "`(`", "`$`"])),
+    }
+}
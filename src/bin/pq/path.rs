@@ -0,0 +1,743 @@
+//! Queries read as locations in a document instead of values, so that
+//! `a.b = (1)`, `a.b |= (_ + 1)` and `del(a.b)` can change what they point at
+//! and `--with-path` can tell where each result was found
+
+use pyo3::prelude::*;
+use serde_json::Value;
+
+use crate::ast::{Pipeline, Query, Spanned};
+use crate::python::eval_python;
+use crate::{evaluate, process_query, type_name, Env, PqError, Span};
+
+/// One step down from a value into one of its children
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment
+{
+    Key(String),
+    Index(usize),
+}
+
+pub type Path = Vec<Segment>;
+
+/// How far setting an index can grow an array, so that `a[99999999999] = (1)`
+/// fails instead of trying to allocate the whole thing
+const MAX_INDEX: usize = 1 << 20;
+
+/// Every location `pipeline` selects in `root`, in the order it selects them
+pub fn paths(
+    env: &Env,
+    root: &Value,
+    pipeline: &Pipeline,
+) -> Result<Vec<Path>, PqError>
+{
+    let mut paths = vec![];
+    collect(env, root, vec![], &pipeline.steps, &mut paths)?;
+    Ok(paths)
+}
+
+fn collect(
+    env: &Env,
+    value: &Value,
+    path: Path,
+    queries: &[Spanned<Query>],
+    paths: &mut Vec<Path>,
+) -> Result<(), PqError>
+{
+    let Some((query, rest)) = queries.split_first()
+    else
+    {
+        paths.push(path);
+        return Ok(());
+    };
+
+    for (relative, child) in step(env, value, query, false)?
+    {
+        // Only reading can lead nowhere, so there is always a path here
+        let mut path = path.clone();
+        path.extend(relative.into_iter().flatten());
+        collect(env, child, path, rest, paths)?;
+    }
+
+    Ok(())
+}
+
+/// Runs `queries` like `evaluate`, but also keeps track of where in the
+/// document each result came from for as long as every step is a path
+pub fn locate(
+    env: &Env,
+    value: Value,
+    path: Option<Path>,
+    queries: &[Spanned<Query>],
+) -> Result<Vec<(Value, Option<Path>)>, PqError>
+{
+    let Some((query, rest)) = queries.split_first()
+    else
+    {
+        return Ok(vec![(value, path)]);
+    };
+
+    crate::check_strict(env, &value, query)?;
+    let located = match (&query.inner, path)
+    {
+        (inner, Some(path)) if inner.is_path() =>
+        {
+            step(env, &value, query, true)?
+                .into_iter()
+                .map(|(relative, child)| {
+                    let path = relative.map(|relative| {
+                        let mut path = path.clone();
+                        path.extend(relative);
+                        path
+                    });
+                    (child.clone(), path)
+                })
+                .collect()
+        }
+        (Query::Join { branches, collect: false }, path) =>
+        {
+            let mut located = vec![];
+            for branch in branches
+            {
+                let path = path.clone();
+                located.extend(locate(
+                    env,
+                    value.clone(),
+                    path,
+                    &branch.steps,
+                )?);
+            }
+            located
+        }
+        // Assigning gives back the document it was given, changed
+        (Query::Assign { .. }, path) => process_query(env, value, query)
+            .map_err(|err| err.with_span(&query.span))?
+            .into_iter()
+            .map(|value| (value, path.clone()))
+            .collect(),
+        _ => process_query(env, value, query)
+            .map_err(|err| err.with_span(&query.span))?
+            .into_iter()
+            .map(|value| (value, None))
+            .collect(),
+    };
+
+    let mut results = vec![];
+    for (value, path) in located
+    {
+        results.extend(locate(env, value, path, rest)?);
+    }
+
+    Ok(results)
+}
+
+/// Where a single path step leads from `value`, relative to `value`. When
+/// `reading`, an index before the start of an array leads nowhere and gives
+/// `null` like it does in `evaluate`, rather than failing like it has to when
+/// it is being assigned to.
+fn step<'v>(
+    env: &Env,
+    value: &'v Value,
+    query: &Spanned<Query>,
+    reading: bool,
+) -> Result<Vec<(Option<Path>, &'v Value)>, PqError>
+{
+    let type_error =
+        |message: String| PqError::Type { message, span: query.span.clone() };
+
+    let children = match &query.inner
+    {
+        Query::SelectKey { key } => match value
+        {
+            Value::Object(_) | Value::Null =>
+            {
+                vec![(Some(vec![Segment::Key(key.clone())]), &value[key])]
+            }
+            _ =>
+            {
+                return Err(type_error(format!(
+                    "cannot select key `{key}` from {}",
+                    type_name(value)
+                )));
+            }
+        },
+        Query::Index { index } =>
+        {
+            let len = value.as_array().map_or(0, Vec::len);
+            let index = if *index < 0
+            {
+                usize::try_from(len as isize + index).ok()
+            }
+            else
+            {
+                Some(*index as usize)
+            };
+            match (value, index)
+            {
+                (Value::Array(_) | Value::Null, Some(index)) =>
+                {
+                    vec![(Some(vec![Segment::Index(index)]), &value[index])]
+                }
+                (Value::Array(_) | Value::Null, None) if reading =>
+                {
+                    vec![(None, &Value::Null)]
+                }
+                (Value::Array(_) | Value::Null, None) =>
+                {
+                    return Err(type_error(format!(
+                        "index is before the start of {}",
+                        type_name(value)
+                    )));
+                }
+                _ =>
+                {
+                    return Err(type_error(format!(
+                        "cannot index {} with a number",
+                        type_name(value)
+                    )));
+                }
+            }
+        }
+        Query::Fanout => match value
+        {
+            Value::Array(array) => array
+                .iter()
+                .enumerate()
+                .map(|(i, child)| (Some(vec![Segment::Index(i)]), child))
+                .collect(),
+            Value::Object(object) => object
+                .iter()
+                .map(|(key, child)| {
+                    (Some(vec![Segment::Key(key.clone())]), child)
+                })
+                .collect(),
+            _ =>
+            {
+                return Err(type_error(format!(
+                    "cannot fan out over {}",
+                    type_name(value)
+                )));
+            }
+        },
+        Query::Recurse { key } => find_paths(value, key)
+            .into_iter()
+            .map(|path| {
+                let child = path.iter().fold(value, get);
+                (Some(path), child)
+            })
+            .collect(),
+        // `--strict` failures are skipped like any other, as in `evaluate`
+        Query::Optional { step: inner } =>
+        {
+            match crate::check_strict(env, value, inner)
+                .and_then(|()| step(env, value, inner, reading))
+            {
+                Err(PqError::Type { .. } | PqError::Python { .. }) => vec![],
+                result => result?,
+            }
+        }
+        Query::Select { source } =>
+        {
+            let keep = Python::with_gil::<_, Result<bool, PqError>>(|py| {
+                let result = eval_python(py, env, value, source)?;
+                Ok(result.is_truthy()?)
+            })
+            .map_err(|err| err.with_span(&query.span))?;
+            if keep
+            {
+                vec![(Some(vec![]), value)]
+            }
+            else
+            {
+                vec![]
+            }
+        }
+        _ =>
+        {
+            return Err(type_error(format!(
+                "cannot assign to `{}`, it is not a path",
+                query.inner
+            )));
+        }
+    };
+
+    Ok(children)
+}
+
+/// Every path below `value` in document order, or only those that end at
+/// something other than an array or object
+pub fn all_paths(value: &Value, leaves_only: bool) -> Vec<Path>
+{
+    let mut paths = vec![];
+    walk(value, &mut vec![], leaves_only, &mut paths);
+    paths
+}
+
+fn walk(
+    value: &Value,
+    path: &mut Path,
+    leaves_only: bool,
+    paths: &mut Vec<Path>,
+)
+{
+    let children: Vec<(Segment, &Value)> = match value
+    {
+        Value::Object(object) => object
+            .iter()
+            .map(|(key, child)| (Segment::Key(key.clone()), child))
+            .collect(),
+        Value::Array(array) => array
+            .iter()
+            .enumerate()
+            .map(|(i, child)| (Segment::Index(i), child))
+            .collect(),
+        _ => vec![],
+    };
+
+    for (segment, child) in children
+    {
+        path.push(segment);
+        let leaf = !matches!(child, Value::Object(_) | Value::Array(_));
+        if leaf || !leaves_only
+        {
+            paths.push(path.clone());
+        }
+        walk(child, path, leaves_only, paths);
+        path.pop();
+    }
+}
+
+/// What is at `path`, or `null` for anything missing along the way
+pub fn get_path(
+    value: &Value,
+    path: &Path,
+    span: &Span,
+) -> Result<Value, PqError>
+{
+    let mut value = value;
+    for segment in path
+    {
+        value = match (segment, value)
+        {
+            (_, Value::Null) => &Value::Null,
+            (Segment::Key(_), Value::Object(_))
+            | (Segment::Index(_), Value::Array(_)) => get(value, segment),
+            (Segment::Key(key), value) =>
+            {
+                return Err(PqError::Type {
+                    message: format!(
+                        "cannot select key `{key}` from {}",
+                        type_name(value)
+                    ),
+                    span: span.clone(),
+                });
+            }
+            (Segment::Index(_), value) =>
+            {
+                return Err(PqError::Type {
+                    message: format!(
+                        "cannot index {} with a number",
+                        type_name(value)
+                    ),
+                    span: span.clone(),
+                });
+            }
+        };
+    }
+
+    Ok(value.clone())
+}
+
+/// `["items", 3, "name"]`
+pub fn to_json(path: &Path) -> Value
+{
+    path.iter()
+        .map(|segment| match segment
+        {
+            Segment::Key(key) => Value::from(key.as_str()),
+            Segment::Index(index) => Value::from(*index),
+        })
+        .collect()
+}
+
+pub fn from_json(value: &Value, span: &Span) -> Result<Path, PqError>
+{
+    let segments = match value
+    {
+        Value::Array(segments) => segments,
+        _ =>
+        {
+            return Err(PqError::Type {
+                message: format!(
+                    "a path is an array of keys and indices, not {}",
+                    type_name(value)
+                ),
+                span: span.clone(),
+            });
+        }
+    };
+
+    segments
+        .iter()
+        .map(|segment| match segment
+        {
+            Value::String(key) => Ok(Segment::Key(key.clone())),
+            Value::Number(index) if index.as_u64().is_some() =>
+            {
+                Ok(Segment::Index(index.as_u64().unwrap_or_default() as usize))
+            }
+            _ => Err(PqError::Type {
+                message: format!(
+                    "a path can only hold keys and indices, not {}",
+                    type_name(segment)
+                ),
+                span: span.clone(),
+            }),
+        })
+        .collect()
+}
+
+/// Paths to every value stored under `key`, in the order `find_key` finds
+/// them
+fn find_paths(value: &Value, key: &str) -> Vec<Path>
+{
+    let mut paths = vec![];
+    let children: Vec<(Segment, &Value)> = match value
+    {
+        Value::Object(object) =>
+        {
+            if object.contains_key(key)
+            {
+                paths.push(vec![Segment::Key(key.to_string())]);
+            }
+            object
+                .iter()
+                .map(|(key, child)| (Segment::Key(key.clone()), child))
+                .collect()
+        }
+        Value::Array(array) => array
+            .iter()
+            .enumerate()
+            .map(|(i, child)| (Segment::Index(i), child))
+            .collect(),
+        _ => vec![],
+    };
+
+    for (segment, child) in children
+    {
+        for mut path in find_paths(child, key)
+        {
+            path.insert(0, segment.clone());
+            paths.push(path);
+        }
+    }
+
+    paths
+}
+
+fn get<'a>(value: &'a Value, segment: &Segment) -> &'a Value
+{
+    match segment
+    {
+        Segment::Key(key) => &value[key],
+        Segment::Index(index) => &value[*index],
+    }
+}
+
+/// Runs `f` on whatever is at `path`, creating objects and arrays on the way
+/// down wherever there was `null`
+pub fn update(
+    value: &mut Value,
+    path: &[Segment],
+    span: &Span,
+    f: &mut impl FnMut(Value) -> Result<Value, PqError>,
+) -> Result<(), PqError>
+{
+    let Some((segment, rest)) = path.split_first()
+    else
+    {
+        *value = f(value.take())?;
+        return Ok(());
+    };
+
+    let child = match (segment, &mut *value)
+    {
+        (Segment::Key(key), Value::Null) =>
+        {
+            *value = Value::Object(serde_json::Map::new());
+            &mut value[key]
+        }
+        (Segment::Key(key), Value::Object(object)) =>
+        {
+            object.entry(key.clone()).or_insert(Value::Null)
+        }
+        (Segment::Index(index), Value::Null) =>
+        {
+            check_growth(*index, span)?;
+            *value = Value::Array(vec![Value::Null; index + 1]);
+            &mut value[*index]
+        }
+        (Segment::Index(index), Value::Array(array)) =>
+        {
+            if array.len() <= *index
+            {
+                check_growth(*index, span)?;
+                array.resize(index + 1, Value::Null);
+            }
+            &mut array[*index]
+        }
+        (Segment::Key(key), value) =>
+        {
+            return Err(PqError::Type {
+                message: format!(
+                    "cannot set key `{key}` in {}",
+                    type_name(value)
+                ),
+                span: span.clone(),
+            });
+        }
+        (Segment::Index(_), value) =>
+        {
+            return Err(PqError::Type {
+                message: format!("cannot set an index in {}", type_name(value)),
+                span: span.clone(),
+            });
+        }
+    };
+
+    update(child, rest, span, f)
+}
+
+/// Fails on growing an array past `MAX_INDEX`, which items already there are
+/// not held to
+fn check_growth(index: usize, span: &Span) -> Result<(), PqError>
+{
+    if index < MAX_INDEX
+    {
+        return Ok(());
+    }
+
+    Err(PqError::Type {
+        message: format!(
+            "index {index} is out of bounds, arrays can only grow to \
+             {MAX_INDEX} items"
+        ),
+        span: span.clone(),
+    })
+}
+
+/// Removes everything at `paths`, last first so that removing an item from
+/// an array does not shift the indices of the ones still to go
+pub fn delete(value: &mut Value, mut paths: Vec<Path>)
+{
+    paths.sort_by(|a, b| compare(b, a));
+    paths.dedup();
+
+    for path in paths
+    {
+        let Some((last, parents)) = path.split_last()
+        else
+        {
+            *value = Value::Null;
+            continue;
+        };
+
+        let parent = parents.iter().try_fold(&mut *value, |parent, segment| {
+            match (segment, parent)
+            {
+                (Segment::Key(key), Value::Object(object)) =>
+                {
+                    object.get_mut(key)
+                }
+                (Segment::Index(index), Value::Array(array)) =>
+                {
+                    array.get_mut(*index)
+                }
+                _ => None,
+            }
+        });
+
+        match (last, parent)
+        {
+            (Segment::Key(key), Some(Value::Object(object))) =>
+            {
+                object.shift_remove(key);
+            }
+            (Segment::Index(index), Some(Value::Array(array)))
+                if *index < array.len() =>
+            {
+                array.remove(*index);
+            }
+            _ => (),
+        }
+    }
+}
+
+fn compare(a: &Path, b: &Path) -> std::cmp::Ordering
+{
+    for (a, b) in a.iter().zip(b)
+    {
+        let ordering = match (a, b)
+        {
+            (Segment::Index(a), Segment::Index(b)) => a.cmp(b),
+            (Segment::Key(a), Segment::Key(b)) => a.cmp(b),
+            (Segment::Index(_), Segment::Key(_)) => std::cmp::Ordering::Less,
+            (Segment::Key(_), Segment::Index(_)) => std::cmp::Ordering::Greater,
+        };
+        if ordering.is_ne()
+        {
+            return ordering;
+        }
+    }
+
+    a.len().cmp(&b.len())
+}
+
+/// `path = value` sets every path to the results of `value` on the whole
+/// document, `path |= value` replaces every value with what `value` makes of
+/// it, or removes it when `value` gives back nothing
+pub fn assign(
+    env: &Env,
+    mut root: Value,
+    path: &Pipeline,
+    update_in_place: bool,
+    value: &Pipeline,
+    span: &Span,
+) -> Result<Vec<Value>, PqError>
+{
+    let paths = paths(env, &root, path)?;
+
+    if update_in_place
+    {
+        let mut removed = vec![];
+        for path in paths
+        {
+            update(&mut root, &path, span, &mut |old| {
+                let mut results = evaluate(env, old, &value.steps)?;
+                if results.is_empty()
+                {
+                    removed.push(path.clone());
+                    return Ok(Value::Null);
+                }
+                Ok(results.swap_remove(0))
+            })?;
+        }
+        delete(&mut root, removed);
+        return Ok(vec![root]);
+    }
+
+    let mut results = vec![];
+    for new in evaluate(env, root.clone(), &value.steps)?
+    {
+        let mut root = root.clone();
+        for path in &paths
+        {
+            update(&mut root, path, span, &mut |_| Ok(new.clone()))?;
+        }
+        results.push(root);
+    }
+
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use serde_json::json;
+
+    use super::*;
+
+    fn key(key: &str) -> Segment
+    {
+        Segment::Key(key.to_string())
+    }
+
+    fn set(mut value: Value, path: &[Segment], new: Value) -> Value
+    {
+        update(&mut value, path, &(0 .. 0), &mut |_| Ok(new.clone())).unwrap();
+        value
+    }
+
+    #[test]
+    fn update_creates_objects_and_arrays_in_null()
+    {
+        let path = [key("a"), Segment::Index(2), key("b")];
+        assert_eq!(
+            set(Value::Null, &path, json!(1)),
+            json!({"a": [null, null, {"b": 1}]})
+        );
+    }
+
+    #[test]
+    fn update_grows_arrays_and_keeps_the_rest()
+    {
+        let value = json!({"a": [1], "b": 2});
+        let path = [key("a"), Segment::Index(2)];
+        assert_eq!(
+            set(value, &path, json!(3)),
+            json!({"a": [1, null, 3], "b": 2})
+        );
+    }
+
+    #[test]
+    fn update_passes_the_old_value()
+    {
+        let mut value = json!({"a": [1, 2]});
+        let path = [key("a"), Segment::Index(1)];
+        update(&mut value, &path, &(0 .. 0), &mut |old| {
+            Ok(json!(old.as_i64().unwrap() * 10))
+        })
+        .unwrap();
+        assert_eq!(value, json!({"a": [1, 20]}));
+    }
+
+    #[test]
+    fn update_refuses_to_grow_arrays_too_far()
+    {
+        let path = [Segment::Index(MAX_INDEX)];
+        let result = update(&mut json!([]), &path, &(0 .. 0), &mut Ok);
+        assert!(matches!(result, Err(PqError::Type { .. })));
+        let result = update(&mut Value::Null, &path, &(0 .. 0), &mut Ok);
+        assert!(matches!(result, Err(PqError::Type { .. })));
+    }
+
+    #[test]
+    fn update_fails_on_scalars()
+    {
+        let result = update(&mut json!(1), &[key("a")], &(0 .. 0), &mut Ok);
+        assert!(matches!(result, Err(PqError::Type { .. })));
+    }
+
+    #[test]
+    fn delete_removes_later_indices_first()
+    {
+        let mut value = json!([0, 1, 2, 3, 4]);
+        let paths =
+            vec![vec![Segment::Index(1)], vec![Segment::Index(3)], vec![
+                Segment::Index(1),
+            ]];
+        delete(&mut value, paths);
+        assert_eq!(value, json!([0, 2, 4]));
+    }
+
+    #[test]
+    fn delete_removes_children_before_their_parents()
+    {
+        let mut value = json!({"a": [{"b": 1}, 2], "c": 3});
+        let paths = vec![
+            vec![key("a"), Segment::Index(0)],
+            vec![key("a"), Segment::Index(0), key("b")],
+            vec![key("c")],
+            vec![key("missing")],
+        ];
+        delete(&mut value, paths);
+        assert_eq!(value, json!({"a": [2]}));
+    }
+
+    #[test]
+    fn delete_of_the_root_leaves_null()
+    {
+        let mut value = json!({"a": 1});
+        delete(&mut value, vec![vec![]]);
+        assert_eq!(value, Value::Null);
+    }
+}
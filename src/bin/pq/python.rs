@@ -0,0 +1,362 @@
+//! Moving values between JSON and Python, and running the Python parts of a
+//! query
+
+use std::cell::{OnceCell, RefCell};
+use std::collections::HashMap;
+
+use pyo3::exceptions::{PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBool, PyDict, PyFloat, PyInt, PyList, PyString, PyTuple};
+
+use crate::{Env, PqError};
+
+/// One interpreter for the whole run, where each expression is compiled once
+/// no matter how many values it runs on, and every expression shares the same
+/// globals. Python itself only starts once the first expression runs.
+#[derive(Default)]
+pub struct Interpreter
+{
+    builtins: OnceCell<Builtins>,
+
+    /// Code objects by source
+    code: RefCell<HashMap<String, Py<PyAny>>>,
+
+    /// `--arg` and `--argjson` values, converted the first time they are
+    /// needed and then shared by every expression
+    args: OnceCell<Py<PyDict>>,
+}
+
+struct Builtins
+{
+    globals: Py<PyDict>,
+    compile: Py<PyAny>,
+    eval: Py<PyAny>,
+}
+
+impl Interpreter
+{
+    fn builtins(&self, py: Python<'_>) -> PyResult<&Builtins>
+    {
+        if let Some(builtins) = self.builtins.get()
+        {
+            return Ok(builtins);
+        }
+
+        let module = py.import_bound("builtins")?;
+        let globals = PyDict::new_bound(py);
+        globals.set_item("__builtins__", &module)?;
+
+        let builtins = Builtins {
+            globals: globals.unbind(),
+            compile: module.getattr("compile")?.unbind(),
+            eval: module.getattr("eval")?.unbind(),
+        };
+        Ok(self.builtins.get_or_init(|| builtins))
+    }
+
+    /// Imports the module at `path` into the globals under its file name,
+    /// where every expression can call it
+    pub fn import(&self, py: Python<'_>, path: &str) -> PyResult<()>
+    {
+        let path = std::path::Path::new(path);
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        let dir = dir.unwrap_or(std::path::Path::new("."));
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+
+        let sys_path = py.import_bound("sys")?.getattr("path")?;
+        sys_path.call_method1("insert", (0, dir))?;
+        let module = py.import_bound(name.as_ref())?;
+
+        let globals = self.builtins(py)?.globals.bind(py);
+        globals.set_item(name.as_ref(), module)
+    }
+
+    fn args<'py>(
+        &self,
+        py: Python<'py>,
+        args: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<Bound<'py, PyDict>, PqError>
+    {
+        if let Some(dict) = self.args.get()
+        {
+            return Ok(dict.bind(py).clone());
+        }
+
+        let dict = PyDict::new_bound(py);
+        for (name, value) in args
+        {
+            dict.set_item(name, to_python(py, value)?)?;
+        }
+        Ok(self.args.get_or_init(|| dict.unbind()).bind(py).clone())
+    }
+
+    pub fn eval<'py>(
+        &self,
+        py: Python<'py>,
+        source: &str,
+        locals: &Bound<'py, PyDict>,
+    ) -> PyResult<Bound<'py, PyAny>>
+    {
+        let builtins = self.builtins(py)?;
+
+        let code =
+            self.code.borrow().get(source).map(|code| code.clone_ref(py));
+        let code = match code
+        {
+            Some(code) => code,
+            None =>
+            {
+                let code =
+                    builtins.compile.call1(py, (source, "<query>", "eval"))?;
+                self.code
+                    .borrow_mut()
+                    .insert(source.to_string(), code.clone_ref(py));
+                code
+            }
+        };
+
+        let globals = builtins.globals.bind(py);
+        builtins.eval.bind(py).call1((code, globals, locals))
+    }
+}
+
+/// Converts JSON into the matching Python `dict`/`list`/`str`/`int`/`float`/
+/// `bool`/`None`, so no JSON ever has to be spliced into Python source
+pub fn to_python<'py>(
+    py: Python<'py>,
+    value: &serde_json::Value,
+) -> Result<Bound<'py, PyAny>, PqError>
+{
+    Ok(match value
+    {
+        serde_json::Value::Null => py.None().into_bound(py),
+        serde_json::Value::Bool(value) => value.to_object(py).into_bound(py),
+        serde_json::Value::Number(number) =>
+        {
+            if let Some(number) = number.as_i64()
+            {
+                number.to_object(py).into_bound(py)
+            }
+            else if let Some(number) = number.as_u64()
+            {
+                number.to_object(py).into_bound(py)
+            }
+            else
+            {
+                number.as_f64().to_object(py).into_bound(py)
+            }
+        }
+        serde_json::Value::String(string) =>
+        {
+            PyString::new_bound(py, string).into_any()
+        }
+        serde_json::Value::Array(array) =>
+        {
+            let list = PyList::empty_bound(py);
+            for item in array
+            {
+                list.append(to_python(py, item)?)?;
+            }
+            list.into_any()
+        }
+        serde_json::Value::Object(object) =>
+        {
+            let dict = PyDict::new_bound(py);
+            for (key, item) in object
+            {
+                dict.set_item(key, to_python(py, item)?)?;
+            }
+            dict.into_any()
+        }
+    })
+}
+
+/// Converts a Python result back into JSON, accepting what `json.dumps` does
+/// except for ints that do not fit in 64 bits, which JSON numbers here cannot
+/// hold exactly
+pub fn from_python(
+    value: &Bound<'_, PyAny>,
+) -> Result<serde_json::Value, PqError>
+{
+    if value.is_none()
+    {
+        return Ok(serde_json::Value::Null);
+    }
+
+    // `bool` subclasses `int`, so it has to be checked first
+    if let Ok(value) = value.downcast::<PyBool>()
+    {
+        return Ok(serde_json::Value::Bool(value.is_true()));
+    }
+
+    if value.is_instance_of::<PyInt>()
+    {
+        if let Ok(number) = value.extract::<i64>()
+        {
+            return Ok(number.into());
+        }
+        if let Ok(number) = value.extract::<u64>()
+        {
+            return Ok(number.into());
+        }
+        return Err(PyTypeError::new_err(format!(
+            "int {value} does not fit in 64 bits"
+        ))
+        .into());
+    }
+
+    if value.is_instance_of::<PyFloat>()
+    {
+        let number: f64 = value.extract()?;
+        return serde_json::Number::from_f64(number)
+            .map(serde_json::Value::Number)
+            .ok_or_else(|| {
+                PyValueError::new_err(format!("{number} is not valid JSON"))
+                    .into()
+            });
+    }
+
+    if let Ok(string) = value.downcast::<PyString>()
+    {
+        return Ok(serde_json::Value::String(string.to_str()?.to_string()));
+    }
+
+    if let Ok(dict) = value.downcast::<PyDict>()
+    {
+        let mut object = serde_json::Map::new();
+        for (key, item) in dict.iter()
+        {
+            // Like `json.dumps`, numbers, bools and `None` are turned into
+            // strings and any other key is an error
+            let key = match key.downcast::<PyString>()
+            {
+                Ok(key) => key.to_str()?.to_string(),
+                Err(_) => match from_python(&key)?
+                {
+                    serde_json::Value::String(key) => key,
+                    serde_json::Value::Array(_)
+                    | serde_json::Value::Object(_) =>
+                    {
+                        return Err(PyTypeError::new_err(format!(
+                            "keys must be str, int, float, bool or None, not \
+                             {}",
+                            key.get_type().name()?
+                        ))
+                        .into());
+                    }
+                    key => key.to_string(),
+                },
+            };
+            object.insert(key, from_python(&item)?);
+        }
+        return Ok(serde_json::Value::Object(object));
+    }
+
+    if value.is_instance_of::<PyList>() || value.is_instance_of::<PyTuple>()
+    {
+        let array = value
+            .iter()?
+            .map(|item| from_python(&item?))
+            .collect::<Result<_, _>>()?;
+        return Ok(serde_json::Value::Array(array));
+    }
+
+    Err(PyTypeError::new_err(format!(
+        "Object of type {} is not JSON serializable",
+        value.get_type().name()?
+    ))
+    .into())
+}
+
+/// Evaluates a parenthesized Python expression with `_` bound to `json_state`
+pub fn eval_python<'py>(
+    py: Python<'py>,
+    env: &Env,
+    json_state: &serde_json::Value,
+    source: &str,
+) -> Result<Bound<'py, PyAny>, PqError>
+{
+    let locals = locals(py, env, &serde_json::Value::Null)?;
+    eval_with(py, env, locals, json_state, source)
+}
+
+/// The Python keys and values of one object, which also see every field of
+/// the object as a local. The fields are converted once for all of them.
+pub struct ObjectLocals<'a>
+{
+    object: &'a serde_json::Value,
+    locals: OnceCell<Py<PyDict>>,
+}
+
+impl<'a> ObjectLocals<'a>
+{
+    pub fn new(object: &'a serde_json::Value) -> Self
+    {
+        Self { object, locals: OnceCell::new() }
+    }
+
+    /// Like `eval_python`, with the object's fields as locals as well
+    pub fn eval<'py>(
+        &self,
+        py: Python<'py>,
+        env: &Env,
+        json_state: &serde_json::Value,
+        source: &str,
+    ) -> Result<Bound<'py, PyAny>, PqError>
+    {
+        let locals = match self.locals.get()
+        {
+            Some(locals) => locals.bind(py),
+            None =>
+            {
+                let locals = locals(py, env, self.object)?.unbind();
+                self.locals.get_or_init(|| locals).bind(py)
+            }
+        };
+
+        // A copy, so that one expression cannot leave anything for the next
+        eval_with(py, env, locals.copy()?, json_state, source)
+    }
+}
+
+/// The locals of an expression other than `_`: the fields of `object`, then
+/// the arguments and function parameters in scope, which win over fields of
+/// the same name
+fn locals<'py>(
+    py: Python<'py>,
+    env: &Env,
+    object: &serde_json::Value,
+) -> Result<Bound<'py, PyDict>, PqError>
+{
+    let locals = PyDict::new_bound(py);
+    if let Some(fields) = object.as_object()
+    {
+        for (key, value) in fields
+        {
+            locals.set_item(key, to_python(py, value)?)?;
+        }
+    }
+    locals.update(env.python.args(py, &env.args)?.as_mapping())?;
+    if let Some(scope) = env.scopes.borrow().last()
+    {
+        for (name, value) in scope
+        {
+            locals.set_item(name, to_python(py, value)?)?;
+        }
+    }
+    locals.set_item("__file__", env.file.as_deref())?;
+
+    Ok(locals)
+}
+
+fn eval_with<'py>(
+    py: Python<'py>,
+    env: &Env,
+    locals: Bound<'py, PyDict>,
+    json_state: &serde_json::Value,
+    source: &str,
+) -> Result<Bound<'py, PyAny>, PqError>
+{
+    locals.set_item("_", to_python(py, json_state)?)?;
+    Ok(env.python.eval(py, source, &locals)?)
+}
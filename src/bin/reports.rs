@@ -9,7 +9,6 @@ fn main()
     // Generate & choose some colours for each of our elements
     let a = colors.next();
     let b = colors.next();
-    let out = Color::Fixed(81);
 
     Report::build(ReportKind::Warning, "_._", 0)
         .with_code(200123)